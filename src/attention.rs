@@ -0,0 +1,921 @@
+use crate::backend::Backend;
+use crate::pool;
+use crate::tensor::Tensor;
+use std::ops::Range;
+
+// Which keys each query row attends to. Keys are numbered in the order they are passed to
+// attention, oldest first, and query row i of seq is key total_seq - seq + i.
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub enum Mask<'a> {
+    // every key up to the row's own position
+    Causal,
+    // causal, limited to the last `window` keys (the row's own included)
+    SlidingWindow(usize),
+    // every key, for encoder-style use of the weights
+    Bidirectional,
+    // (seq, total_seq), true where a row may attend to a key
+    Custom(&'a Tensor<bool>),
+    // `base` with padding never attended to: the first `left` and the last `right` keys
+    Padded {
+        base: &'a Mask<'a>,
+        left: usize,
+        right: usize,
+    },
+}
+
+impl Mask<'_> {
+    // whether a row only sees keys up to its own position, counted from the first, so that an
+    // input can run in chunks, each attending to the cache the ones before it filled
+    pub fn is_causal(&self) -> bool {
+        match *self {
+            Mask::Causal | Mask::SlidingWindow(_) => true,
+            Mask::Padded { base, right, .. } => right == 0 && base.is_causal(),
+            Mask::Bidirectional | Mask::Custom(_) => false,
+        }
+    }
+
+    // smallest range of keys holding every key row i may attend to
+    pub fn visible(&self, i: usize, seq_len: usize, total_seq_len: usize) -> Range<usize> {
+        let end = total_seq_len - seq_len + i + 1;
+        match *self {
+            Mask::Causal => 0..end,
+            Mask::SlidingWindow(window) => end.saturating_sub(window)..end,
+            Mask::Bidirectional => 0..total_seq_len,
+            Mask::Custom(mask) => {
+                let row = &mask.data()[i * total_seq_len..][..total_seq_len];
+                match (row.iter().position(|&m| m), row.iter().rposition(|&m| m)) {
+                    (Some(first), Some(last)) => first..last + 1,
+                    _ => 0..0,
+                }
+            }
+            Mask::Padded { base, left, right } => {
+                let range = base.visible(i, seq_len, total_seq_len);
+                let start = range.start.max(left);
+                start
+                    ..range
+                        .end
+                        .min(total_seq_len.saturating_sub(right))
+                        .max(start)
+            }
+        }
+    }
+
+    // whether row i may attend to key j, for a key inside visible(i)
+    pub fn allows(&self, i: usize, j: usize, total_seq_len: usize) -> bool {
+        match *self {
+            Mask::Custom(mask) => mask.data()[i * total_seq_len + j],
+            Mask::Padded { base, .. } => base.allows(i, j, total_seq_len),
+            _ => true,
+        }
+    }
+
+    // every key in visible(i) is allowed, no need to ask `allows`
+    pub fn is_range(&self) -> bool {
+        match *self {
+            Mask::Custom(_) => false,
+            Mask::Padded { base, .. } => base.is_range(),
+            _ => true,
+        }
+    }
+
+    pub fn check_shape(&self, seq_len: usize, total_seq_len: usize) {
+        match *self {
+            Mask::Custom(mask) => {
+                assert_eq!(mask.shape(), &vec![seq_len, total_seq_len], "mask shape")
+            }
+            Mask::Padded { base, .. } => base.check_shape(seq_len, total_seq_len),
+            _ => {}
+        }
+    }
+}
+
+// Query rows and kv positions handled together: the Q_BLOCK rows of a tile go over the same
+// KV_BLOCK keys and values one after the other, so they are read from cache rather than memory.
+// Each row and head still computes its own dot products with them.
+const Q_BLOCK: usize = 16;
+const KV_BLOCK: usize = 64;
+
+// Running softmax-weighted sum of V rows for one query row and head: after any prefix of the
+// keys, acc / sum is the attention output restricted to those keys.
+struct OnlineSoftmax {
+    max: f32,
+    sum: f32,
+    acc: Vec<f32>, // (dqkv)
+}
+
+impl OnlineSoftmax {
+    fn new(dqkv: usize) -> Self {
+        OnlineSoftmax {
+            max: f32::NEG_INFINITY,
+            sum: 0.,
+            acc: vec![0.; dqkv],
+        }
+    }
+
+    // fold in `keys` with the given scores, reading head h of their value rows
+    fn update(&mut self, scores: &[f32], values: &impl Rows, keys: Range<usize>, h: usize) {
+        let block_max = scores.iter().fold(f32::NEG_INFINITY, |m, &s| m.max(s));
+        if block_max == f32::NEG_INFINITY {
+            // every key masked out
+            return;
+        }
+        if block_max > self.max {
+            // rescale what was accumulated against the old maximum
+            let correction = (self.max - block_max).exp();
+            self.sum *= correction;
+            self.acc.iter_mut().for_each(|a| *a *= correction);
+            self.max = block_max;
+        }
+        for (&s, j) in scores.iter().zip(keys) {
+            let p = (s - self.max).exp();
+            self.sum += p;
+            values.accumulate(&mut self.acc, p, j, h);
+        }
+    }
+
+    // combine with the state of a disjoint set of keys
+    fn merge(&mut self, other: &OnlineSoftmax) {
+        if other.sum == 0. {
+            return;
+        }
+        let max = self.max.max(other.max);
+        let (a, b) = ((self.max - max).exp(), (other.max - max).exp());
+        self.sum = self.sum * a + other.sum * b;
+        self.acc
+            .iter_mut()
+            .zip(&other.acc)
+            .for_each(|(x, y)| *x = *x * a + y * b);
+        self.max = max;
+    }
+
+    // a row that may not attend to anything gets zeros
+    fn finish(&self, out: &mut [f32]) {
+        let scale = if self.sum > 0. { 1. / self.sum } else { 0. };
+        out.iter_mut()
+            .zip(&self.acc)
+            .for_each(|(o, a)| *o = a * scale);
+    }
+}
+
+// out = softmax(q k^T / sqrt(dqkv) + mask) v without materialising the scores: keys are
+// consumed KV_BLOCK at a time with an online softmax.
+// q, out: (seq, n_q_h * dqkv); k, v: (total_seq, n_kv_h * dqkv), the last seq positions being
+// the ones of q; query head h reads kv head h / (n_q_h / n_kv_h)
+#[allow(clippy::too_many_arguments)]
+pub fn fused(
+    out: &mut Tensor<f32>,
+    q: &Tensor<f32>,
+    k: &Tensor<f32>,
+    v: &Tensor<f32>,
+    n_kv_h: usize,
+    dqkv: usize,
+    mask: Mask<'_>,
+) {
+    fused_threads(out, q, k, v, n_kv_h, dqkv, mask, 1);
+}
+
+// below this many keys per thread splitting the cache is not worth it
+const MIN_KEYS_PER_THREAD: usize = 256;
+
+// `fused` on up to `threads` threads. A prompt is split by query rows. A short query (decode)
+// is split by key ranges instead, flash-decoding style: every thread folds its share of the
+// cache into partial softmax states, which are merged in key order afterwards.
+#[allow(clippy::too_many_arguments)]
+pub fn fused_threads<'a>(
+    out: &mut Tensor<f32>,
+    q: &'a Tensor<f32>,
+    k: &'a Tensor<f32>,
+    v: &'a Tensor<f32>,
+    n_kv_h: usize,
+    dqkv: usize,
+    mask: Mask<'_>,
+    threads: usize,
+) {
+    let (seq_len, total_seq_len, n_q_h) = check_shapes(out, q, k, v, n_kv_h, dqkv, mask);
+    let (k, v) = ([k.data()], [v.data()]);
+    let rows = |blocks| Dense {
+        blocks,
+        block_size: total_seq_len,
+        start: 0,
+        n_kv_h,
+        dqkv,
+    };
+    let problem = Problem {
+        q: q.data(),
+        k: rows(&k),
+        v: rows(&v),
+        n_q_h,
+        n_kv_h,
+        dqkv,
+        seq_len,
+        total_seq_len,
+        mask,
+    };
+    problem.run(out, threads);
+}
+
+// Keys and values stored in fixed-size blocks of a shared pool: position j of the sequence is
+// row p % block_size of block p / block_size, p = j + start wrapping around the blocks, as in a
+// ring buffer that has a single block.
+pub struct Paged<'a, T> {
+    pub k: Vec<&'a [T]>, // (block_size, n_kv_h * dqkv) rows of every block of the sequence, in order
+    pub v: Vec<&'a [T]>,
+    pub block_size: usize,
+    pub start: usize, // row of position 0, 0 unless the rows wrap around
+    pub len: usize,   // positions of the sequence
+}
+
+// no derive: T itself need not be Clone
+impl<T> Clone for Paged<'_, T> {
+    fn clone(&self) -> Self {
+        Paged {
+            k: self.k.clone(),
+            v: self.v.clone(),
+            block_size: self.block_size,
+            start: self.start,
+            len: self.len,
+        }
+    }
+}
+
+impl Paged<'_, f32> {
+    // contiguous (len, n_kv_h * dqkv) copies of the keys and values, in position order
+    pub fn gather(&self) -> (Tensor<f32>, Tensor<f32>) {
+        let dim = self
+            .k
+            .first()
+            .map_or(0, |block| block.len() / self.block_size);
+        let copy = |blocks: &[&[f32]]| {
+            let mut rows = Vec::with_capacity(self.len * dim);
+            let rows_held = blocks.len() * self.block_size;
+            for p in (0..self.len).map(|j| (self.start + j) % rows_held) {
+                rows.extend_from_slice(
+                    &blocks[p / self.block_size][p % self.block_size * dim..][..dim],
+                );
+            }
+            Tensor::new(rows, &vec![self.len, dim])
+        };
+        (copy(&self.k), copy(&self.v))
+    }
+}
+
+// `fused_threads` reading keys and values through a block table instead of from contiguous rows
+#[allow(clippy::too_many_arguments)]
+pub fn paged<'a>(
+    out: &mut Tensor<f32>,
+    q: &'a Tensor<f32>,
+    kv: &Paged<'a, f32>,
+    n_kv_h: usize,
+    dqkv: usize,
+    mask: Mask<'_>,
+    threads: usize,
+) {
+    let (seq_len, total_seq_len) = (q.shape()[0], kv.len);
+    let n_q_h = q.shape()[1] / dqkv;
+    assert!(
+        n_q_h.is_multiple_of(n_kv_h),
+        "{n_q_h} query heads cannot share {n_kv_h} kv heads"
+    );
+    assert!(seq_len <= total_seq_len);
+    assert_eq!(q.shape(), &vec![seq_len, n_q_h * dqkv]);
+    assert!(
+        kv.k.len() * kv.block_size >= total_seq_len,
+        "block table too short"
+    );
+    assert!(kv.start < kv.k.len() * kv.block_size);
+    assert_eq!(kv.v.len(), kv.k.len());
+    let block_len = kv.block_size * n_kv_h * dqkv;
+    assert!(kv
+        .k
+        .iter()
+        .chain(&kv.v)
+        .all(|block| block.len() == block_len));
+    assert_eq!(out.shape(), q.shape());
+    mask.check_shape(seq_len, total_seq_len);
+    let rows = |blocks| Dense {
+        blocks,
+        block_size: kv.block_size,
+        start: kv.start,
+        n_kv_h,
+        dqkv,
+    };
+    let problem = Problem {
+        q: q.data(),
+        k: rows(&kv.k),
+        v: rows(&kv.v),
+        n_q_h,
+        n_kv_h,
+        dqkv,
+        seq_len,
+        total_seq_len,
+        mask,
+    };
+    problem.run(out, threads);
+}
+
+// the operands of one fused attention call
+struct Problem<'a, R> {
+    q: &'a [f32],
+    k: R,
+    v: R,
+    n_q_h: usize,
+    n_kv_h: usize,
+    dqkv: usize,
+    seq_len: usize,
+    total_seq_len: usize,
+    mask: Mask<'a>,
+}
+
+impl<R: Rows> Problem<'_, R> {
+    // on up to `threads` threads, see fused_threads
+    fn run(&self, out: &mut Tensor<f32>, threads: usize) {
+        let (seq_len, total_seq_len) = (self.seq_len, self.total_seq_len);
+        let row_len = self.n_q_h * self.dqkv;
+        let out = unsafe { out.data_mut() };
+        let threads = threads
+            .min(seq_len * total_seq_len / MIN_KEYS_PER_THREAD)
+            .max(1);
+
+        if threads == 1 {
+            self.finish(&self.partial(0..seq_len, 0..total_seq_len), out);
+        } else if seq_len >= threads {
+            let rows_per = seq_len.div_ceil(threads);
+            let bands = out.chunks_mut(rows_per * row_len).enumerate().collect();
+            pool::map(bands, threads, |(t, band): (usize, &mut [f32])| {
+                let rows = t * rows_per..t * rows_per + band.len() / row_len;
+                self.finish(&self.partial(rows, 0..total_seq_len), band)
+            });
+        } else {
+            let threads = threads.min(total_seq_len / MIN_KEYS_PER_THREAD).max(1);
+            let keys_per = total_seq_len.div_ceil(threads);
+            let keys = (0..total_seq_len)
+                .step_by(keys_per)
+                .map(|j0| j0..(j0 + keys_per).min(total_seq_len))
+                .collect();
+            let partials = pool::map(keys, threads, |keys| self.partial(0..seq_len, keys));
+            let mut partials = partials.into_iter();
+            let mut states = partials.next().unwrap();
+            for part in partials {
+                states.iter_mut().zip(&part).for_each(|(s, p)| s.merge(p));
+            }
+            self.finish(&states, out);
+        }
+    }
+
+    // states of query rows `rows` over the keys in `keys` only, (rows.len() * n_q_h) of them
+    // with head h of row i at (i - rows.start) * n_q_h + h
+    fn partial(&self, rows: Range<usize>, keys: Range<usize>) -> Vec<OnlineSoftmax> {
+        let (n_q_h, n_kv_h, dqkv) = (self.n_q_h, self.n_kv_h, self.dqkv);
+        let n_groups = n_q_h / n_kv_h;
+        let scale = 1. / (dqkv as f32).sqrt();
+        let visible = |i: usize| self.mask.visible(i, self.seq_len, self.total_seq_len);
+        let exact = self.mask.is_range();
+        let mut states = (0..rows.len() * n_q_h)
+            .map(|_| OnlineSoftmax::new(dqkv))
+            .collect::<Vec<_>>();
+        let mut scores = [0f32; KV_BLOCK];
+        for kh in 0..n_kv_h {
+            for i0 in rows.clone().step_by(Q_BLOCK) {
+                let tile = i0..(i0 + Q_BLOCK).min(rows.end);
+                // keys any row of the tile can see
+                let ranges = tile.clone().map(visible).filter(|r| !r.is_empty());
+                let first = ranges
+                    .clone()
+                    .map(|r| r.start)
+                    .min()
+                    .unwrap_or(0)
+                    .max(keys.start);
+                let last = ranges.map(|r| r.end).max().unwrap_or(0).min(keys.end);
+                for j0 in (first..last).step_by(KV_BLOCK) {
+                    for i in tile.clone() {
+                        let row = visible(i);
+                        let visible = j0.max(row.start)..row.end.min(j0 + KV_BLOCK).min(keys.end);
+                        if visible.is_empty() {
+                            continue;
+                        }
+                        for h in kh * n_groups..(kh + 1) * n_groups {
+                            let q_row = &self.q[(i * n_q_h + h) * dqkv..][..dqkv];
+                            let scores = &mut scores[..visible.len()];
+                            for (s, j) in scores.iter_mut().zip(visible.clone()) {
+                                *s = if exact || self.mask.allows(i, j, self.total_seq_len) {
+                                    self.k.dot(q_row, j, kh) * scale
+                                } else {
+                                    f32::NEG_INFINITY
+                                };
+                            }
+                            states[(i - rows.start) * n_q_h + h].update(
+                                scores,
+                                &self.v,
+                                visible.clone(),
+                                kh,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        states
+    }
+
+    // write the (rows, n_q_h * dqkv) outputs of finished states
+    fn finish(&self, states: &[OnlineSoftmax], out: &mut [f32]) {
+        for (state, out) in states.iter().zip(out.chunks_exact_mut(self.dqkv)) {
+            state.finish(out);
+        }
+    }
+}
+
+// Rows of cached keys or values as attention reads them, by position j and kv head h
+trait Rows: Sync {
+    // q . row
+    fn dot(&self, q: &[f32], j: usize, h: usize) -> f32;
+    // acc += p * row
+    fn accumulate(&self, acc: &mut [f32], p: f32, j: usize, h: usize);
+}
+
+// f32 rows in blocks of block_size, position j being row j + start of the blocks, wrapping around
+struct Dense<'a> {
+    blocks: &'a [&'a [f32]],
+    block_size: usize,
+    start: usize,
+    n_kv_h: usize,
+    dqkv: usize,
+}
+
+impl Dense<'_> {
+    fn row(&self, j: usize, h: usize) -> &[f32] {
+        let mut p = j + self.start;
+        if p >= self.blocks.len() * self.block_size {
+            p -= self.blocks.len() * self.block_size;
+        }
+        let block = self.blocks[p / self.block_size];
+        &block[(p % self.block_size * self.n_kv_h + h) * self.dqkv..][..self.dqkv]
+    }
+}
+
+impl Rows for Dense<'_> {
+    fn dot(&self, q: &[f32], j: usize, h: usize) -> f32 {
+        dot(q, self.row(j, h))
+    }
+
+    fn accumulate(&self, acc: &mut [f32], p: f32, j: usize, h: usize) {
+        acc.iter_mut()
+            .zip(self.row(j, h))
+            .for_each(|(a, v)| *a += p * v);
+    }
+}
+
+// int8 rows with a scale per position and head, dequantized on the fly
+struct Quantized<'a> {
+    data: &'a [i8],
+    scales: &'a [f32], // (total_seq, n_kv_h)
+    n_kv_h: usize,
+    dqkv: usize,
+}
+
+impl Quantized<'_> {
+    fn row(&self, j: usize, h: usize) -> (&[i8], f32) {
+        let i = j * self.n_kv_h + h;
+        (&self.data[i * self.dqkv..][..self.dqkv], self.scales[i])
+    }
+}
+
+impl Rows for Quantized<'_> {
+    fn dot(&self, q: &[f32], j: usize, h: usize) -> f32 {
+        let (row, scale) = self.row(j, h);
+        q.iter().zip(row).map(|(x, &y)| x * y as f32).sum::<f32>() * scale
+    }
+
+    fn accumulate(&self, acc: &mut [f32], p: f32, j: usize, h: usize) {
+        let (row, scale) = self.row(j, h);
+        let p = p * scale;
+        acc.iter_mut()
+            .zip(row)
+            .for_each(|(a, &v)| *a += p * v as f32);
+    }
+}
+
+// Keys and values quantized to int8 with one scale per position and kv head, see
+// quant::quantize_i8
+#[derive(Clone)]
+pub struct Int8 {
+    pub k: Tensor<i8>, // (total_seq, n_kv_h * dqkv)
+    pub v: Tensor<i8>,
+    pub k_scale: Tensor<f32>, // (total_seq, n_kv_h)
+    pub v_scale: Tensor<f32>,
+}
+
+impl Int8 {
+    // the f32 keys and values the int8 ones stand for
+    pub fn dequantize(&self) -> (Tensor<f32>, Tensor<f32>) {
+        let dequantize = |q: &Tensor<i8>, scale: &Tensor<f32>| {
+            let dqkv = q.shape()[1] / scale.shape()[1];
+            let data = q.data().chunks_exact(dqkv).zip(scale.data());
+            let data = data
+                .flat_map(|(row, &d)| row.iter().map(move |&x| x as f32 * d))
+                .collect();
+            Tensor::new(data, q.shape())
+        };
+        (
+            dequantize(&self.k, &self.k_scale),
+            dequantize(&self.v, &self.v_scale),
+        )
+    }
+}
+
+// `fused_threads` on int8 keys and values
+#[allow(clippy::too_many_arguments)]
+pub fn int8<'a>(
+    out: &mut Tensor<f32>,
+    q: &'a Tensor<f32>,
+    kv: &'a Int8,
+    n_kv_h: usize,
+    dqkv: usize,
+    mask: Mask<'a>,
+    threads: usize,
+) {
+    let (seq_len, total_seq_len, n_q_h) = check_shapes(out, q, &kv.k, &kv.v, n_kv_h, dqkv, mask);
+    assert_eq!(kv.k_scale.shape(), &vec![total_seq_len, n_kv_h]);
+    assert_eq!(kv.v_scale.shape(), kv.k_scale.shape());
+    let rows = |data: &'a Tensor<i8>, scales: &'a Tensor<f32>| Quantized {
+        data: data.data(),
+        scales: scales.data(),
+        n_kv_h,
+        dqkv,
+    };
+    let problem = Problem {
+        q: q.data(),
+        k: rows(&kv.k, &kv.k_scale),
+        v: rows(&kv.v, &kv.v_scale),
+        n_q_h,
+        n_kv_h,
+        dqkv,
+        seq_len,
+        total_seq_len,
+        mask,
+    };
+    problem.run(out, threads);
+}
+
+// The textbook evaluation on a backend's own kernels: the (seq, total_seq) score matrix of each
+// head is computed with matmul_transb, normalised with masked_softmax and multiplied by V.
+#[allow(clippy::too_many_arguments)]
+pub fn materialized<B: Backend + ?Sized>(
+    backend: &B,
+    out: &mut Tensor<f32>,
+    q: &Tensor<f32>,
+    k: &Tensor<f32>,
+    v: &Tensor<f32>,
+    n_kv_h: usize,
+    dqkv: usize,
+    mask: Mask<'_>,
+) {
+    let (seq_len, total_seq_len, n_q_h) = check_shapes(out, q, k, v, n_kv_h, dqkv, mask);
+    let n_groups = n_q_h / n_kv_h;
+    let mut scores = Tensor::<f32>::default(&vec![seq_len, total_seq_len]);
+    let mut head_out = Tensor::<f32>::default(&vec![seq_len, dqkv]);
+    // 1 kv -> n_groups consecutive q heads
+    for h in 0..n_kv_h {
+        // zero-copy views: (total_seq, dqkv) and (dqkv, total_seq)
+        let k_head = k.select_head(h, n_kv_h, dqkv);
+        let v_head_trans = v.select_head(h, n_kv_h, dqkv).transpose(0, 1);
+        for g in 0..n_groups {
+            let q_head = h * n_groups + g;
+            // score = Q @ K.T / sqrt(dim)
+            let q_slice = q.select_head(q_head, n_q_h, dqkv);
+            backend.matmul_transb(
+                &mut scores,
+                0.,
+                &q_slice,
+                &k_head,
+                1. / (dqkv as f32).sqrt(),
+            );
+            // attn = softmax(score)
+            backend.masked_softmax(&mut scores, mask);
+            // attn_V = attn @ V
+            backend.matmul_transb(&mut head_out, 0., &scores, &v_head_trans, 1.);
+            // write back in the (seq, n_q_h * dqkv) layout expected by O_weight
+            let out_data = unsafe { out.data_mut() };
+            for (i, row) in head_out.data().chunks_exact(dqkv).enumerate() {
+                out_data[(i * n_q_h + q_head) * dqkv..][..dqkv].copy_from_slice(row);
+            }
+        }
+    }
+}
+
+// (seq_len, total_seq_len, n_q_h)
+fn check_shapes<T: Copy + Default + Send + Sync + 'static>(
+    out: &Tensor<f32>,
+    q: &Tensor<f32>,
+    k: &Tensor<T>,
+    v: &Tensor<T>,
+    n_kv_h: usize,
+    dqkv: usize,
+    mask: Mask,
+) -> (usize, usize, usize) {
+    let (seq_len, total_seq_len) = (q.shape()[0], k.shape()[0]);
+    let n_q_h = q.shape()[1] / dqkv;
+    assert!(
+        n_q_h.is_multiple_of(n_kv_h),
+        "{n_q_h} query heads cannot share {n_kv_h} kv heads"
+    );
+    assert!(seq_len <= total_seq_len);
+    assert_eq!(q.shape(), &vec![seq_len, n_q_h * dqkv]);
+    assert_eq!(k.shape(), &vec![total_seq_len, n_kv_h * dqkv]);
+    assert_eq!(v.shape(), k.shape());
+    assert_eq!(out.shape(), q.shape());
+    mask.check_shape(seq_len, total_seq_len);
+    (seq_len, total_seq_len, n_q_h)
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(test)]
+use crate::tensor::max_abs_diff;
+
+// random (seq_len, n_q_h * dqkv) queries and (total_seq_len, n_kv_h * dqkv) keys and values
+#[cfg(test)]
+fn random_qkv(
+    rng: &mut rand::rngs::StdRng,
+    seq_len: usize,
+    total_seq_len: usize,
+    n_q_h: usize,
+    n_kv_h: usize,
+    dqkv: usize,
+) -> (Tensor<f32>, Tensor<f32>, Tensor<f32>) {
+    use rand::Rng;
+    let mut fill = |rows: usize, heads: usize| {
+        let data = (0..rows * heads * dqkv)
+            .map(|_| rng.gen_range(-2.0..2.0))
+            .collect();
+        Tensor::new(data, &vec![rows, heads * dqkv])
+    };
+    (
+        fill(seq_len, n_q_h),
+        fill(total_seq_len, n_kv_h),
+        fill(total_seq_len, n_kv_h),
+    )
+}
+
+// the output of the Reference backend
+#[cfg(test)]
+fn reference(
+    q: &Tensor<f32>,
+    k: &Tensor<f32>,
+    v: &Tensor<f32>,
+    n_kv_h: usize,
+    dqkv: usize,
+    mask: Mask,
+) -> Tensor<f32> {
+    let mut out = Tensor::default(q.shape());
+    materialized(
+        &crate::backend::Reference,
+        &mut out,
+        q,
+        k,
+        v,
+        n_kv_h,
+        dqkv,
+        mask,
+    );
+    out
+}
+
+// `attend` into a fresh output on every thread count, within `tolerance` of `expected`
+#[cfg(test)]
+fn assert_on_threads(
+    expected: &Tensor<f32>,
+    threads: &[usize],
+    tolerance: f32,
+    attend: impl Fn(&mut Tensor<f32>, usize),
+) {
+    for &threads in threads {
+        let mut out = Tensor::default(expected.shape());
+        attend(&mut out, threads);
+        let err = max_abs_diff(out.data(), expected.data());
+        assert!(
+            err < tolerance,
+            "{:?} on {threads} threads: {err}",
+            expected.shape()
+        );
+    }
+}
+
+#[test]
+fn test_fused_attention() {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(13);
+    // prefill and decode, with kv lengths around the block sizes
+    for (seq_len, total_seq_len, n_q_h, n_kv_h, dqkv) in [
+        (1, 1, 2, 1, 4),
+        (5, 5, 4, 4, 8),
+        (1, 200, 8, 2, 16),
+        (40, 130, 4, 2, 8),
+        (17, 64, 2, 1, 3),
+    ] {
+        let (q, k, v) = random_qkv(&mut rng, seq_len, total_seq_len, n_q_h, n_kv_h, dqkv);
+        let expected = reference(&q, &k, &v, n_kv_h, dqkv, Mask::Causal);
+        assert_on_threads(&expected, &[1], 1e-5, |out, _| {
+            fused(out, &q, &k, &v, n_kv_h, dqkv, Mask::Causal)
+        });
+    }
+}
+
+#[test]
+fn test_split_kv_attention() {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(14);
+    // decode over a long cache is split by keys, prompts by rows
+    for (seq_len, total_seq_len, n_q_h, n_kv_h, dqkv) in
+        [(1, 3000, 8, 2, 16), (2, 1100, 4, 4, 8), (64, 600, 4, 1, 8)]
+    {
+        let (q, k, v) = random_qkv(&mut rng, seq_len, total_seq_len, n_q_h, n_kv_h, dqkv);
+        let expected = reference(&q, &k, &v, n_kv_h, dqkv, Mask::Causal);
+        assert_on_threads(&expected, &[1, 2, 3, 8], 1e-5, |out, threads| {
+            fused_threads(out, &q, &k, &v, n_kv_h, dqkv, Mask::Causal, threads)
+        });
+    }
+}
+
+#[test]
+fn test_sliding_window_attention() {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(15);
+    assert_eq!(Mask::SlidingWindow(3).visible(1, 4, 10), 5..8);
+    assert_eq!(Mask::SlidingWindow(30).visible(1, 4, 10), 0..8);
+    for (seq_len, total_seq_len, window) in
+        [(1, 700, 100), (90, 90, 7), (20, 150, 64), (3, 3000, 1000)]
+    {
+        let (n_q_h, n_kv_h, dqkv) = (4, 2, 8);
+        let (q, k, v) = random_qkv(&mut rng, seq_len, total_seq_len, n_q_h, n_kv_h, dqkv);
+        let mask = Mask::SlidingWindow(window);
+        let expected = reference(&q, &k, &v, n_kv_h, dqkv, mask);
+        assert_on_threads(&expected, &[1, 3], 1e-5, |out, threads| {
+            fused_threads(out, &q, &k, &v, n_kv_h, dqkv, mask, threads)
+        });
+        // the last row only depends on the last `window` keys
+        let mut causal = Tensor::<f32>::default(&vec![seq_len, n_q_h * dqkv]);
+        fused(&mut causal, &q, &k, &v, n_kv_h, dqkv, Mask::Causal);
+        let last = |t: &Tensor<f32>| t.data()[(seq_len - 1) * n_q_h * dqkv..].to_vec();
+        assert_eq!(last(&causal) == last(&expected), total_seq_len <= window);
+    }
+}
+
+#[test]
+fn test_custom_masks() {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(16);
+    let (seq_len, total_seq_len, n_q_h, n_kv_h, dqkv) = (20, 150, 4, 2, 8);
+    let (q, k, v) = random_qkv(&mut rng, seq_len, total_seq_len, n_q_h, n_kv_h, dqkv);
+    let run = |mask: Mask, threads: usize| {
+        let mut out = Tensor::<f32>::default(&vec![seq_len, n_q_h * dqkv]);
+        fused_threads(&mut out, &q, &k, &v, n_kv_h, dqkv, mask, threads);
+        out
+    };
+
+    // a boolean causal mask is the causal mask
+    let causal = (0..seq_len)
+        .flat_map(|i| (0..total_seq_len).map(move |j| j <= total_seq_len - seq_len + i))
+        .collect();
+    let causal = Tensor::new(causal, &vec![seq_len, total_seq_len]);
+    assert!(
+        max_abs_diff(
+            run(Mask::Custom(&causal), 1).data(),
+            run(Mask::Causal, 1).data()
+        ) < 1e-6
+    );
+
+    // random masks, with row 3 masked out entirely
+    let mut random = (0..seq_len * total_seq_len)
+        .map(|_| rng.gen_bool(0.3))
+        .collect::<Vec<_>>();
+    random[3 * total_seq_len..4 * total_seq_len].fill(false);
+    let random = Tensor::new(random, &vec![seq_len, total_seq_len]);
+    let padded = Mask::Padded {
+        base: &Mask::Causal,
+        left: 5,
+        right: 0,
+    };
+    let padded_custom = Mask::Padded {
+        base: &Mask::Custom(&random),
+        left: 10,
+        right: 7,
+    };
+    for mask in [
+        Mask::Custom(&random),
+        Mask::Bidirectional,
+        padded,
+        padded_custom,
+    ] {
+        let expected = reference(&q, &k, &v, n_kv_h, dqkv, mask);
+        assert_on_threads(&expected, &[1, 2], 1e-5, |out, threads| {
+            fused_threads(out, &q, &k, &v, n_kv_h, dqkv, mask, threads)
+        });
+    }
+    let out = run(Mask::Custom(&random), 1);
+    assert!(out.data()[3 * n_q_h * dqkv..4 * n_q_h * dqkv]
+        .iter()
+        .all(|&x| x == 0.));
+}
+
+#[test]
+fn test_paged_attention() {
+    fn blocks<'a>(pool: &'a [f32], table: &[usize], len: usize) -> Vec<&'a [f32]> {
+        table.iter().map(|&b| &pool[b * len..][..len]).collect()
+    }
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(17);
+    let (n_q_h, n_kv_h, dqkv, block_size) = (4, 2, 8, 16);
+    for (seq_len, total_seq_len) in [(1, 1), (1, 700), (30, 100), (5, 37)] {
+        let (q, k, v) = random_qkv(&mut rng, seq_len, total_seq_len, n_q_h, n_kv_h, dqkv);
+        // the blocks of the sequence scattered over a pool with a few spare ones
+        let n_blocks = total_seq_len.div_ceil(block_size);
+        let mut table = (0..n_blocks + 3).collect::<Vec<_>>();
+        table.shuffle(&mut rng);
+        table.truncate(n_blocks);
+        let row_len = n_kv_h * dqkv;
+        let mut pool_k = vec![f32::NAN; (n_blocks + 3) * block_size * row_len];
+        let mut pool_v = pool_k.clone();
+        for j in 0..total_seq_len {
+            let row = (table[j / block_size] * block_size + j % block_size) * row_len;
+            pool_k[row..][..row_len].copy_from_slice(&k.data()[j * row_len..][..row_len]);
+            pool_v[row..][..row_len].copy_from_slice(&v.data()[j * row_len..][..row_len]);
+        }
+        let (k_blocks, v_blocks) = (
+            blocks(&pool_k, &table, block_size * row_len),
+            blocks(&pool_v, &table, block_size * row_len),
+        );
+        let kv = Paged {
+            k: k_blocks,
+            v: v_blocks,
+            block_size,
+            start: 0,
+            len: total_seq_len,
+        };
+        assert_eq!(kv.gather().0.data(), k.data());
+
+        for mask in [Mask::Causal, Mask::SlidingWindow(20)] {
+            let mut expected = Tensor::<f32>::default(&vec![seq_len, n_q_h * dqkv]);
+            fused(&mut expected, &q, &k, &v, n_kv_h, dqkv, mask);
+            assert_on_threads(&expected, &[1, 3], 1e-5, |out, threads| {
+                paged(out, &q, &kv, n_kv_h, dqkv, mask, threads)
+            });
+        }
+    }
+}
+
+#[test]
+fn test_int8_attention() {
+    use crate::quant::quantize_i8;
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(18);
+    let (n_q_h, n_kv_h, dqkv) = (4, 2, 16);
+    for (seq_len, total_seq_len) in [(1, 1), (1, 700), (30, 100)] {
+        let (q, k, v) = random_qkv(&mut rng, seq_len, total_seq_len, n_q_h, n_kv_h, dqkv);
+        let quantize = |t: &Tensor<f32>| {
+            let mut q = vec![0i8; t.size()];
+            let scales = t
+                .data()
+                .chunks_exact(dqkv)
+                .zip(q.chunks_exact_mut(dqkv))
+                .map(|(x, q)| quantize_i8(x, q))
+                .collect();
+            (
+                Tensor::new(q, t.shape()),
+                Tensor::new(scales, &vec![total_seq_len, n_kv_h]),
+            )
+        };
+        let ((k8, k_scale), (v8, v_scale)) = (quantize(&k), quantize(&v));
+        let kv = Int8 {
+            k: k8,
+            v: v8,
+            k_scale,
+            v_scale,
+        };
+
+        // exact against the dequantized values, close to the f32 ones
+        let (k_deq, v_deq) = kv.dequantize();
+        assert!(max_abs_diff(k_deq.data(), k.data()) <= 2. / 127. / 2. + 1e-6);
+        let mut expected = Tensor::<f32>::default(&vec![seq_len, n_q_h * dqkv]);
+        fused(
+            &mut expected,
+            &q,
+            &k_deq,
+            &v_deq,
+            n_kv_h,
+            dqkv,
+            Mask::Causal,
+        );
+        let mut exact = Tensor::<f32>::default(&vec![seq_len, n_q_h * dqkv]);
+        fused(&mut exact, &q, &k, &v, n_kv_h, dqkv, Mask::Causal);
+        let attend = |out: &mut Tensor<f32>, threads| {
+            int8(out, &q, &kv, n_kv_h, dqkv, Mask::Causal, threads)
+        };
+        assert_on_threads(&expected, &[1, 3], 1e-5, attend);
+        assert_on_threads(&exact, &[1, 3], 0.05, attend);
+    }
+}
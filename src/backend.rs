@@ -0,0 +1,576 @@
+use crate::attention::{self, Int8, Mask, Paged};
+use crate::gemm::Isa;
+use crate::operators as OP;
+use crate::quant::{self, QTensor, Weight};
+use crate::rope::RopeLayout;
+use crate::tensor::{Float, Tensor};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+// The kernels a forward pass is made of. `Llama` only calls its backend, so any of the
+// implementations below can be plugged in without changing the model code.
+pub trait Backend: Send + Sync {
+    fn gather<W: Float>(&self, y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<W>);
+    fn rope(&self, y: &mut Tensor<f32>, cos: &Tensor<f32>, sin: &Tensor<f32>, layout: RopeLayout);
+    fn masked_softmax(&self, y: &mut Tensor<f32>, mask: Mask);
+    fn rms_norm<W: Float>(&self, y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<W>, epsilon: f32);
+    fn swiglu(&self, y: &mut Tensor<f32>, x: &Tensor<f32>);
+    fn matmul_transb<W: Float>(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        b: &Tensor<W>,
+        alpha: f32,
+    );
+
+    // block-quantized B; there is a single kernel for it unless a backend brings its own
+    fn matmul_transb_q(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        b: &QTensor,
+        alpha: f32,
+    ) {
+        quant::matmul_transb_q(c, beta, a, b, alpha);
+    }
+
+    // out = softmax(q k^T / sqrt(dqkv) + mask) v for all heads at once, see attention::fused
+    #[allow(clippy::too_many_arguments)]
+    fn attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        k: &Tensor<f32>,
+        v: &Tensor<f32>,
+        n_kv_h: usize,
+        dqkv: usize,
+        mask: Mask,
+    ) {
+        attention::fused(out, q, k, v, n_kv_h, dqkv, mask);
+    }
+
+    // `attention` over keys and values in the blocks of a paged cache, see attention::paged
+    fn paged_attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        kv: &Paged<f32>,
+        n_kv_h: usize,
+        dqkv: usize,
+        mask: Mask,
+    ) {
+        attention::paged(out, q, kv, n_kv_h, dqkv, mask, 1);
+    }
+
+    // `attention` over int8 keys and values, see attention::int8
+    fn int8_attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        kv: &Int8,
+        n_kv_h: usize,
+        dqkv: usize,
+        mask: Mask,
+    ) {
+        attention::int8(out, q, kv, n_kv_h, dqkv, mask, 1);
+    }
+
+    // gather from a dense or block-quantized embedding table
+    fn embedding<T: Float>(&self, y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Weight<T>) {
+        match table {
+            Weight::Dense(t) => self.gather(y, indices, t),
+            Weight::Quant(q) => {
+                let dim = q.shape()[1];
+                assert!(y.size() == indices.size() * dim);
+                let y_data = unsafe { y.data_mut() };
+                for (i, &idx) in indices.data().iter().enumerate() {
+                    q.dequantize_row(idx as usize, &mut y_data[i * dim..][..dim]);
+                }
+            }
+        }
+    }
+
+    // C = beta * C + alpha * A @ W^T for a dense or block-quantized weight
+    fn linear<T: Float>(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        w: &Weight<T>,
+        alpha: f32,
+    ) {
+        match w {
+            Weight::Dense(b) => self.matmul_transb(c, beta, a, b, alpha),
+            Weight::Quant(b) => self.matmul_transb_q(c, beta, a, b, alpha),
+        }
+    }
+}
+
+// Plain scalar loops on the calling thread: slow, but the easiest code to trust. Only the
+// matmuls and attention have kernels of their own here; gather, rope, masked_softmax, rms_norm,
+// swiglu and matmul_transb_q are the same operators Optimized runs.
+#[allow(unused)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Reference;
+
+impl Backend for Reference {
+    fn gather<W: Float>(&self, y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<W>) {
+        OP::gather(y, indices, table);
+    }
+
+    fn rope(&self, y: &mut Tensor<f32>, cos: &Tensor<f32>, sin: &Tensor<f32>, layout: RopeLayout) {
+        OP::rope(y, cos, sin, layout);
+    }
+
+    fn masked_softmax(&self, y: &mut Tensor<f32>, mask: Mask) {
+        OP::masked_softmax(y, mask);
+    }
+
+    fn rms_norm<W: Float>(
+        &self,
+        y: &mut Tensor<f32>,
+        x: &Tensor<f32>,
+        w: &Tensor<W>,
+        epsilon: f32,
+    ) {
+        OP::rms_norm(y, x, w, epsilon);
+    }
+
+    fn swiglu(&self, y: &mut Tensor<f32>, x: &Tensor<f32>) {
+        OP::swiglu(y, x);
+    }
+
+    fn matmul_transb<W: Float>(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        b: &Tensor<W>,
+        alpha: f32,
+    ) {
+        OP::matmul_transb_isa(c, beta, a, b, alpha, 1, Isa::Scalar);
+    }
+
+    // the full score matrix of each head, as written down in the paper
+    #[allow(clippy::too_many_arguments)]
+    fn attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        k: &Tensor<f32>,
+        v: &Tensor<f32>,
+        n_kv_h: usize,
+        dqkv: usize,
+        mask: Mask,
+    ) {
+        attention::materialized(self, out, q, k, v, n_kv_h, dqkv, mask);
+    }
+
+    // copied out of the blocks first
+    fn paged_attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        kv: &Paged<f32>,
+        n_kv_h: usize,
+        dqkv: usize,
+        mask: Mask,
+    ) {
+        let (k, v) = kv.gather();
+        self.attention(out, q, &k, &v, n_kv_h, dqkv, mask);
+    }
+
+    // dequantized first
+    fn int8_attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        kv: &Int8,
+        n_kv_h: usize,
+        dqkv: usize,
+        mask: Mask,
+    ) {
+        let (k, v) = kv.dequantize();
+        self.attention(out, q, &k, &v, n_kv_h, dqkv, mask);
+    }
+}
+
+// Multi-threaded matmuls on the best SIMD kernels of this CPU and multi-threaded attention.
+// The default backend.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Optimized;
+
+impl Backend for Optimized {
+    fn gather<W: Float>(&self, y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<W>) {
+        OP::gather(y, indices, table);
+    }
+
+    fn rope(&self, y: &mut Tensor<f32>, cos: &Tensor<f32>, sin: &Tensor<f32>, layout: RopeLayout) {
+        OP::rope(y, cos, sin, layout);
+    }
+
+    fn masked_softmax(&self, y: &mut Tensor<f32>, mask: Mask) {
+        OP::masked_softmax(y, mask);
+    }
+
+    fn rms_norm<W: Float>(
+        &self,
+        y: &mut Tensor<f32>,
+        x: &Tensor<f32>,
+        w: &Tensor<W>,
+        epsilon: f32,
+    ) {
+        OP::rms_norm(y, x, w, epsilon);
+    }
+
+    fn swiglu(&self, y: &mut Tensor<f32>, x: &Tensor<f32>) {
+        OP::swiglu(y, x);
+    }
+
+    fn matmul_transb<W: Float>(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        b: &Tensor<W>,
+        alpha: f32,
+    ) {
+        OP::matmul_transb(c, beta, a, b, alpha);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        k: &Tensor<f32>,
+        v: &Tensor<f32>,
+        n_kv_h: usize,
+        dqkv: usize,
+        mask: Mask,
+    ) {
+        attention::fused_threads(out, q, k, v, n_kv_h, dqkv, mask, OP::num_threads());
+    }
+
+    fn paged_attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        kv: &Paged<f32>,
+        n_kv_h: usize,
+        dqkv: usize,
+        mask: Mask,
+    ) {
+        attention::paged(out, q, kv, n_kv_h, dqkv, mask, OP::num_threads());
+    }
+
+    fn int8_attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        kv: &Int8,
+        n_kv_h: usize,
+        dqkv: usize,
+        mask: Mask,
+    ) {
+        attention::int8(out, q, kv, n_kv_h, dqkv, mask, OP::num_threads());
+    }
+}
+
+// How far a candidate backend strayed from the reference for one kind of operator
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Divergence {
+    pub calls: usize,
+    pub failures: usize, // calls whose error exceeded the tolerance
+    pub max_err: f32,    // max over calls of |reference - candidate| / (1 + |reference|)
+}
+
+// One call whose error exceeded the tolerance
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Failure {
+    pub op: &'static str,
+    pub call: usize, // index among the calls of op
+    pub err: f32,
+}
+
+// Runs every operator on both backends and records how far the candidate's output is from the
+// reference's. The reference result is the one passed on, so errors do not compound and each
+// operator is measured on the same inputs. With Reference against Optimized, only
+// matmul_transb, attention, paged_attention and int8_attention compare two implementations.
+pub struct Checking<A, B> {
+    reference: A,
+    candidate: B,
+    tolerance: f32,
+    stats: Mutex<BTreeMap<&'static str, Divergence>>,
+    failures: Mutex<Vec<Failure>>,
+}
+
+#[allow(unused)]
+impl<A: Backend, B: Backend> Checking<A, B> {
+    pub fn new(reference: A, candidate: B, tolerance: f32) -> Self {
+        Self {
+            reference,
+            candidate,
+            tolerance,
+            stats: Mutex::new(BTreeMap::new()),
+            failures: Mutex::new(Vec::new()),
+        }
+    }
+
+    // per-operator divergence seen so far, ordered by operator name
+    pub fn report(&self) -> Vec<(&'static str, Divergence)> {
+        self.stats
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&op, &d)| (op, d))
+            .collect()
+    }
+
+    // every call that exceeded the tolerance so far, in call order
+    pub fn failures(&self) -> Vec<Failure> {
+        self.failures.lock().unwrap().clone()
+    }
+
+    fn record(&self, op: &'static str, expected: &Tensor<f32>, actual: &Tensor<f32>) {
+        let err = expected
+            .data()
+            .iter()
+            .zip(actual.data())
+            .fold(0f32, |m, (x, y)| m.max((x - y).abs() / (1. + x.abs())));
+        let failed = err > self.tolerance || err.is_nan();
+        let mut stats = self.stats.lock().unwrap();
+        let d = stats.entry(op).or_default();
+        if failed {
+            self.failures.lock().unwrap().push(Failure {
+                op,
+                call: d.calls,
+                err,
+            });
+        }
+        d.calls += 1;
+        d.failures += failed as usize;
+        d.max_err = d.max_err.max(err);
+    }
+}
+
+impl<A: Backend + Default, B: Backend + Default> Default for Checking<A, B> {
+    fn default() -> Self {
+        Self::new(A::default(), B::default(), 1e-4)
+    }
+}
+
+// a packed copy of y for the candidate to work on
+fn copy(y: &Tensor<f32>) -> Tensor<f32> {
+    Tensor::new(y.data().to_vec(), y.shape())
+}
+
+impl<A: Backend, B: Backend> Backend for Checking<A, B> {
+    fn gather<W: Float>(&self, y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<W>) {
+        let mut y2 = copy(y);
+        self.reference.gather(y, indices, table);
+        self.candidate.gather(&mut y2, indices, table);
+        self.record("gather", y, &y2);
+    }
+
+    fn rope(&self, y: &mut Tensor<f32>, cos: &Tensor<f32>, sin: &Tensor<f32>, layout: RopeLayout) {
+        let mut y2 = copy(y);
+        self.reference.rope(y, cos, sin, layout);
+        self.candidate.rope(&mut y2, cos, sin, layout);
+        self.record("rope", y, &y2);
+    }
+
+    fn masked_softmax(&self, y: &mut Tensor<f32>, mask: Mask) {
+        let mut y2 = copy(y);
+        self.reference.masked_softmax(y, mask);
+        self.candidate.masked_softmax(&mut y2, mask);
+        self.record("masked_softmax", y, &y2);
+    }
+
+    fn rms_norm<W: Float>(
+        &self,
+        y: &mut Tensor<f32>,
+        x: &Tensor<f32>,
+        w: &Tensor<W>,
+        epsilon: f32,
+    ) {
+        let mut y2 = copy(y);
+        self.reference.rms_norm(y, x, w, epsilon);
+        self.candidate.rms_norm(&mut y2, x, w, epsilon);
+        self.record("rms_norm", y, &y2);
+    }
+
+    fn swiglu(&self, y: &mut Tensor<f32>, x: &Tensor<f32>) {
+        let mut y2 = copy(y);
+        self.reference.swiglu(y, x);
+        self.candidate.swiglu(&mut y2, x);
+        self.record("swiglu", y, &y2);
+    }
+
+    fn matmul_transb<W: Float>(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        b: &Tensor<W>,
+        alpha: f32,
+    ) {
+        let mut c2 = copy(c);
+        self.reference.matmul_transb(c, beta, a, b, alpha);
+        self.candidate.matmul_transb(&mut c2, beta, a, b, alpha);
+        self.record("matmul_transb", c, &c2);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        k: &Tensor<f32>,
+        v: &Tensor<f32>,
+        n_kv_h: usize,
+        dqkv: usize,
+        mask: Mask,
+    ) {
+        let mut out2 = copy(out);
+        self.reference.attention(out, q, k, v, n_kv_h, dqkv, mask);
+        self.candidate
+            .attention(&mut out2, q, k, v, n_kv_h, dqkv, mask);
+        self.record("attention", out, &out2);
+    }
+
+    fn paged_attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        kv: &Paged<f32>,
+        n_kv_h: usize,
+        dqkv: usize,
+        mask: Mask,
+    ) {
+        let mut out2 = copy(out);
+        self.reference
+            .paged_attention(out, q, kv, n_kv_h, dqkv, mask);
+        self.candidate
+            .paged_attention(&mut out2, q, kv, n_kv_h, dqkv, mask);
+        self.record("paged_attention", out, &out2);
+    }
+
+    fn int8_attention(
+        &self,
+        out: &mut Tensor<f32>,
+        q: &Tensor<f32>,
+        kv: &Int8,
+        n_kv_h: usize,
+        dqkv: usize,
+        mask: Mask,
+    ) {
+        let mut out2 = copy(out);
+        self.reference
+            .int8_attention(out, q, kv, n_kv_h, dqkv, mask);
+        self.candidate
+            .int8_attention(&mut out2, q, kv, n_kv_h, dqkv, mask);
+        self.record("int8_attention", out, &out2);
+    }
+
+    fn matmul_transb_q(
+        &self,
+        c: &mut Tensor<f32>,
+        beta: f32,
+        a: &Tensor<f32>,
+        b: &QTensor,
+        alpha: f32,
+    ) {
+        let mut c2 = copy(c);
+        self.reference.matmul_transb_q(c, beta, a, b, alpha);
+        self.candidate.matmul_transb_q(&mut c2, beta, a, b, alpha);
+        self.record("matmul_transb_q", c, &c2);
+    }
+}
+
+#[test]
+fn test_checking_reports_divergence() {
+    // a candidate whose swiglu forgets the gate
+    struct NoGate;
+    impl Backend for NoGate {
+        fn gather<W: Float>(&self, y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<W>) {
+            Reference.gather(y, indices, table)
+        }
+        fn rope(
+            &self,
+            y: &mut Tensor<f32>,
+            cos: &Tensor<f32>,
+            sin: &Tensor<f32>,
+            layout: RopeLayout,
+        ) {
+            Reference.rope(y, cos, sin, layout)
+        }
+        fn masked_softmax(&self, y: &mut Tensor<f32>, mask: Mask) {
+            Reference.masked_softmax(y, mask)
+        }
+        fn rms_norm<W: Float>(
+            &self,
+            y: &mut Tensor<f32>,
+            x: &Tensor<f32>,
+            w: &Tensor<W>,
+            epsilon: f32,
+        ) {
+            Reference.rms_norm(y, x, w, epsilon)
+        }
+        fn swiglu(&self, y: &mut Tensor<f32>, x: &Tensor<f32>) {
+            let x = x.data();
+            unsafe { y.data_mut() }
+                .iter_mut()
+                .zip(x)
+                .for_each(|(y, x)| *y *= x);
+        }
+        fn matmul_transb<W: Float>(
+            &self,
+            c: &mut Tensor<f32>,
+            beta: f32,
+            a: &Tensor<f32>,
+            b: &Tensor<W>,
+            alpha: f32,
+        ) {
+            Reference.matmul_transb(c, beta, a, b, alpha)
+        }
+    }
+
+    let checking = Checking::new(Reference, NoGate, 1e-4);
+    let mut y = Tensor::<f32>::new(vec![2., 3., 4.], &vec![1, 3]);
+    let x = Tensor::<f32>::new(vec![1., 2., 3.], &vec![1, 3]);
+    checking.swiglu(&mut y, &x);
+    // the reference result is the one kept
+    assert!(y.close_to(
+        &Tensor::<f32>::new(vec![1.4621172, 5.2847824, 11.43089], &vec![1, 3]),
+        1e-3
+    ));
+    let mut c = Tensor::<f32>::default(&vec![1, 1]);
+    checking.linear(&mut c, 0., &x, &Weight::Dense(x.clone()), 1.);
+    assert_eq!(c.data(), &[14.]);
+
+    let report = checking.report();
+    assert_eq!(report[0].0, "matmul_transb");
+    assert_eq!(
+        report[0].1,
+        Divergence {
+            calls: 1,
+            failures: 0,
+            max_err: 0.
+        }
+    );
+    assert_eq!(report[1].0, "swiglu");
+    assert_eq!(report[1].1.failures, 1);
+    assert!(report[1].1.max_err > 0.1);
+    let failures = checking.failures();
+    assert_eq!(
+        failures,
+        [Failure {
+            op: "swiglu",
+            call: 0,
+            err: report[1].1.max_err
+        }]
+    );
+}
@@ -1,4 +1,3 @@
-use serde;
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub(crate) struct LlamaConfigJson {
     pub bos_token_id: u32,
@@ -17,6 +16,60 @@ pub(crate) struct LlamaConfigJson {
     pub torch_dtype: String,
     #[serde(default = "default_tie_word_embeddings")]
     pub tie_word_embeddings: bool,
+    #[serde(default)]
+    pub rope_scaling: Option<RopeScalingJson>,
+    #[serde(default)]
+    pub model_type: Option<String>,
+    // fraction of each head that is rotated (Phi); GPT-NeoX calls it rotary_pct
+    #[serde(default, alias = "rotary_pct")]
+    pub partial_rotary_factor: Option<f32>,
+    // number of rotated dimensions per head (GPT-J), takes precedence over the factor
+    #[serde(default)]
+    pub rotary_dim: Option<usize>,
+    // Mistral: each token attends to at most this many positions, itself included
+    #[serde(default)]
+    pub sliding_window: Option<usize>,
+    // Qwen2 writes a sliding_window it does not use unless this is set
+    #[serde(default)]
+    pub use_sliding_window: Option<bool>,
+}
+
+impl LlamaConfigJson {
+    // the sliding window attention actually uses, if any
+    pub fn attention_window(&self) -> Option<usize> {
+        self.sliding_window
+            .filter(|_| self.use_sliding_window != Some(false))
+    }
+}
+
+// `rope_scaling` as written by transformers; which fields are used depends on the type
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub(crate) struct RopeScalingJson {
+    // older configs call it `type`, newer ones `rope_type`, some write both
+    #[serde(default)]
+    pub rope_type: Option<String>,
+    #[serde(default, rename = "type")]
+    pub legacy_type: Option<String>,
+    #[serde(default)]
+    pub factor: Option<f32>,
+    #[serde(default)]
+    pub original_max_position_embeddings: Option<usize>,
+    // llama3
+    #[serde(default)]
+    pub low_freq_factor: Option<f32>,
+    #[serde(default)]
+    pub high_freq_factor: Option<f32>,
+    // yarn
+    #[serde(default)]
+    pub beta_fast: Option<f32>,
+    #[serde(default)]
+    pub beta_slow: Option<f32>,
+    #[serde(default)]
+    pub attention_factor: Option<f32>,
+    #[serde(default)]
+    pub mscale: Option<f32>,
+    #[serde(default)]
+    pub mscale_all_dim: Option<f32>,
 }
 
 #[inline(always)]
@@ -0,0 +1,215 @@
+use std::ops::Range;
+use std::sync::OnceLock;
+
+// Instruction sets the f32 matmul micro-kernels are compiled for, picked at runtime
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Isa {
+    Scalar,
+    Avx2,   // avx2 + fma, 8 lanes
+    Avx512, // avx512f, 16 lanes
+}
+
+// Best instruction set supported by this CPU, detected once.
+pub fn isa() -> Isa {
+    static ISA: OnceLock<Isa> = OnceLock::new();
+    *ISA.get_or_init(|| *available().last().unwrap())
+}
+
+// Every instruction set this CPU can run, from slowest to fastest.
+pub fn available() -> Vec<Isa> {
+    #[allow(unused_mut)]
+    let mut isas = vec![Isa::Scalar];
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            isas.push(Isa::Avx2);
+        }
+        if is_x86_feature_detected!("avx512f") {
+            isas.push(Isa::Avx512);
+        }
+    }
+    isas
+}
+
+// micro-tile of C held in registers
+const MR: usize = 4;
+const NR: usize = 4;
+// cache blocks: NC rows of B are reused across MC rows of A before moving on
+const MC: usize = 64;
+const NC: usize = 64;
+
+// C[rows, cols] = beta * C + alpha * A[rows] @ B[cols]^T for row-major f32 A and B with unit
+// inner stride. `c` starts at element (rows.start, cols.start) and has row stride `ldc`.
+// Each element's sum is accumulated in the same lane order whichever micro-tile shape covers
+// it, so the result does not depend on how the output is split into tiles.
+#[allow(clippy::too_many_arguments)]
+pub fn tile_f32(
+    isa: Isa,
+    c: &mut [f32],
+    ldc: usize,
+    rows: Range<usize>,
+    cols: Range<usize>,
+    beta: f32,
+    a: &[f32],
+    lda: usize,
+    b: &[f32],
+    ldb: usize,
+    k: usize,
+    alpha: f32,
+) {
+    assert_ne!(isa, Isa::Scalar, "scalar matmul has its own loop");
+    let mut update = |i: usize, j: usize, sum: f32| {
+        let c_ij = &mut c[(i - rows.start) * ldc + (j - cols.start)];
+        *c_ij = beta * *c_ij + alpha * sum;
+    };
+    for jb in cols.clone().step_by(NC) {
+        let jb_end = (jb + NC).min(cols.end);
+        for ib in rows.clone().step_by(MC) {
+            let ib_end = (ib + MC).min(rows.end);
+            let mut i = ib;
+            while i < ib_end {
+                let mr = if ib_end - i >= MR { MR } else { 1 };
+                let mut j = jb;
+                while j < jb_end {
+                    let nr = if jb_end - j >= NR { NR } else { 1 };
+                    let a_blk = &a[i * lda..];
+                    let b_blk = &b[j * ldb..];
+                    match (mr, nr) {
+                        (MR, NR) => {
+                            let sums = block::<MR, NR>(isa, a_blk, lda, b_blk, ldb, k);
+                            for (di, row) in sums.iter().enumerate() {
+                                row.iter()
+                                    .enumerate()
+                                    .for_each(|(dj, &s)| update(i + di, j + dj, s));
+                            }
+                        }
+                        (MR, 1) => {
+                            let sums = block::<MR, 1>(isa, a_blk, lda, b_blk, ldb, k);
+                            sums.iter()
+                                .enumerate()
+                                .for_each(|(di, s)| update(i + di, j, s[0]));
+                        }
+                        (1, NR) => {
+                            let sums = block::<1, NR>(isa, a_blk, lda, b_blk, ldb, k);
+                            sums[0]
+                                .iter()
+                                .enumerate()
+                                .for_each(|(dj, &s)| update(i, j + dj, s));
+                        }
+                        _ => update(i, j, block::<1, 1>(isa, a_blk, lda, b_blk, ldb, k)[0][0]),
+                    }
+                    j += nr;
+                }
+                i += mr;
+            }
+        }
+    }
+}
+
+// dot products of R rows of A against C rows of B
+fn block<const R: usize, const C: usize>(
+    isa: Isa,
+    a: &[f32],
+    lda: usize,
+    b: &[f32],
+    ldb: usize,
+    k: usize,
+) -> [[f32; C]; R] {
+    assert!(a.len() >= (R - 1) * lda + k && b.len() >= (C - 1) * ldb + k);
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        match isa {
+            Isa::Avx512 => return x86::block_avx512::<R, C>(a, lda, b, ldb, k),
+            Isa::Avx2 => return x86::block_avx2::<R, C>(a, lda, b, ldb, k),
+            Isa::Scalar => {}
+        }
+    }
+    unreachable!("{isa:?} kernels are not available on this target")
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    // horizontal lane sum in a fixed order, then the k % lanes tail
+    #[inline(always)]
+    fn finish<const L: usize>(lanes: [f32; L], a: &[f32], b: &[f32], from: usize, k: usize) -> f32 {
+        let mut sum = lanes.iter().fold(0.0, |s, x| s + x);
+        for l in from..k {
+            sum += a[l] * b[l];
+        }
+        sum
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn block_avx2<const R: usize, const C: usize>(
+        a: &[f32],
+        lda: usize,
+        b: &[f32],
+        ldb: usize,
+        k: usize,
+    ) -> [[f32; C]; R] {
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let kv = k / 8 * 8;
+        let mut acc = [[_mm256_setzero_ps(); C]; R];
+        let mut l = 0;
+        while l < kv {
+            let mut bv = [_mm256_setzero_ps(); C];
+            for (j, v) in bv.iter_mut().enumerate() {
+                *v = _mm256_loadu_ps(pb.add(j * ldb + l));
+            }
+            for (i, acc_row) in acc.iter_mut().enumerate() {
+                let av = _mm256_loadu_ps(pa.add(i * lda + l));
+                for (acc_ij, &bj) in acc_row.iter_mut().zip(&bv) {
+                    *acc_ij = _mm256_fmadd_ps(av, bj, *acc_ij);
+                }
+            }
+            l += 8;
+        }
+        let mut out = [[0.0; C]; R];
+        for i in 0..R {
+            for j in 0..C {
+                let mut lanes = [0.0; 8];
+                _mm256_storeu_ps(lanes.as_mut_ptr(), acc[i][j]);
+                out[i][j] = finish(lanes, &a[i * lda..], &b[j * ldb..], kv, k);
+            }
+        }
+        out
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub unsafe fn block_avx512<const R: usize, const C: usize>(
+        a: &[f32],
+        lda: usize,
+        b: &[f32],
+        ldb: usize,
+        k: usize,
+    ) -> [[f32; C]; R] {
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let kv = k / 16 * 16;
+        let mut acc = [[_mm512_setzero_ps(); C]; R];
+        let mut l = 0;
+        while l < kv {
+            let mut bv = [_mm512_setzero_ps(); C];
+            for (j, v) in bv.iter_mut().enumerate() {
+                *v = _mm512_loadu_ps(pb.add(j * ldb + l));
+            }
+            for (i, acc_row) in acc.iter_mut().enumerate() {
+                let av = _mm512_loadu_ps(pa.add(i * lda + l));
+                for (acc_ij, &bj) in acc_row.iter_mut().zip(&bv) {
+                    *acc_ij = _mm512_fmadd_ps(av, bj, *acc_ij);
+                }
+            }
+            l += 16;
+        }
+        let mut out = [[0.0; C]; R];
+        for i in 0..R {
+            for j in 0..C {
+                let mut lanes = [0.0; 16];
+                _mm512_storeu_ps(lanes.as_mut_ptr(), acc[i][j]);
+                out[i][j] = finish(lanes, &a[i * lda..], &b[j * ldb..], kv, k);
+            }
+        }
+        out
+    }
+}
@@ -1,16 +1,310 @@
-use std::{usize, vec};
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::vec;
 
+use safetensors::tensor::TensorView;
+use safetensors::{Dtype, SafeTensors};
+
+use crate::attention::{Int8, Paged};
+use crate::quant;
 use crate::tensor::Tensor;
+
+// Fixed-size blocks of KV storage shared by many caches, so that memory is proportional to the
+// positions actually stored rather than max_seq_len per cache.
+//
+// Caches of the pool read and write it concurrently, so the rows are never handed out as one
+// buffer: a block is read by its holders and written only by its one holder, see `write`, who
+// copies it first if it is shared, see `make_unique`.
+pub struct BlockPool<T> {
+    k: Vec<Box<[UnsafeCell<T>]>>, // (n_blocks * block_size * dim) x layers
+    v: Vec<Box<[UnsafeCell<T>]>>, // (n_blocks * block_size * dim) x layers
+    n_blocks: usize,
+    block_size: usize, // positions per block
+    dim: usize,
+    blocks: Mutex<Blocks>,
+}
+
+// Safety: a block is only written by a holder that is the only one, which `write` checks under
+// the lock, and a block with a single holder is only reachable through that holder's cache.
+unsafe impl<T: Send + Sync> Sync for BlockPool<T> {}
+
+// Blocks can be shared, e.g. by caches starting with the same prompt: a block is free again
+// once every holder has released it.
+struct Blocks {
+    free: Vec<usize>,
+    refs: Vec<usize>, // holders of each block
+}
+
+// A paged cache needed more blocks than its pool had free
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolExhausted {
+    pub needed: usize,
+    pub free: usize,
+}
+
+impl std::fmt::Display for PoolExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} KV blocks needed, {} free in the pool",
+            self.needed, self.free
+        )
+    }
+}
+
+impl std::error::Error for PoolExhausted {}
+
+impl<T: Default + Copy + Send + Sync + 'static> BlockPool<T> {
+    pub fn new(n_layers: usize, n_blocks: usize, block_size: usize, dim: usize) -> Self {
+        assert!(block_size > 0);
+        let rows = n_blocks * block_size;
+        let storage = || {
+            (0..rows * dim)
+                .map(|_| UnsafeCell::new(T::default()))
+                .collect()
+        };
+        BlockPool {
+            k: (0..n_layers).map(|_| storage()).collect(),
+            v: (0..n_layers).map(|_| storage()).collect(),
+            n_blocks,
+            block_size,
+            dim,
+            blocks: Mutex::new(Blocks {
+                // handed out lowest first
+                free: (0..n_blocks).rev().collect(),
+                refs: vec![0; n_blocks],
+            }),
+        }
+    }
+
+    // The (block_size, dim) keys and values of `block` in a layer, for a holder of the block to
+    // read. Nobody writes them while it holds the block: they are either its own or shared.
+    fn block(&self, layer: usize, block: usize) -> (&[T], &[T]) {
+        let len = self.block_size * self.dim;
+        let read = |t: &[UnsafeCell<T>]| {
+            let cells = &t[block * len..][..len];
+            unsafe {
+                std::slice::from_raw_parts(UnsafeCell::raw_get(cells.as_ptr()) as *const T, len)
+            }
+        };
+        (read(&self.k[layer]), read(&self.v[layer]))
+    }
+
+    // Write rows of keys and values to `block` from row `row` on, as its only holder.
+    fn write(&self, layer: usize, block: usize, row: usize, k: &[T], v: &[T]) {
+        let state = self.blocks.lock().unwrap();
+        assert_eq!(
+            state.refs[block], 1,
+            "block {block} is shared or free, copy it first"
+        );
+        assert!(row * self.dim + k.len() <= self.block_size * self.dim && v.len() == k.len());
+        let start = (block * self.block_size + row) * self.dim;
+        for (t, new) in [(&self.k[layer], k), (&self.v[layer], v)] {
+            let cells = &t[start..][..new.len()];
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    new.as_ptr(),
+                    UnsafeCell::raw_get(cells.as_ptr()),
+                    new.len(),
+                )
+            };
+        }
+    }
+
+    // A block of the caller's own with the contents of `block`, before writing to it: `block`
+    // itself unless others hold it too, else a copy, for which the caller's hold on `block` is
+    // given up.
+    fn make_unique(&self, block: usize) -> Result<usize, PoolExhausted> {
+        if self.ref_count(block) == 1 {
+            return Ok(block);
+        }
+        let copy = self.alloc(1)?[0];
+        for layer in 0..self.k.len() {
+            let (k, v) = self.block(layer, block);
+            self.write(layer, copy, 0, k, v);
+        }
+        self.release(&[block]);
+        Ok(copy)
+    }
+}
+
+impl<T> BlockPool<T> {
+    #[allow(unused)]
+    pub fn num_blocks(&self) -> usize {
+        self.n_blocks
+    }
+
+    #[allow(unused)]
+    pub fn num_free(&self) -> usize {
+        self.blocks.lock().unwrap().free.len()
+    }
+
+    // caches and other holders sharing the block
+    #[allow(unused)]
+    pub fn ref_count(&self, block: usize) -> usize {
+        self.blocks.lock().unwrap().refs[block]
+    }
+
+    #[allow(unused)]
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    // blocks needed to hold len positions
+    pub fn blocks_for(&self, len: usize) -> usize {
+        len.div_ceil(self.block_size)
+    }
+
+    // n free blocks, all or none
+    fn alloc(&self, n: usize) -> Result<Vec<usize>, PoolExhausted> {
+        let mut blocks = self.blocks.lock().unwrap();
+        if blocks.free.len() < n {
+            return Err(PoolExhausted {
+                needed: n,
+                free: blocks.free.len(),
+            });
+        }
+        let at = blocks.free.len() - n;
+        let taken = blocks.free.split_off(at);
+        let taken = taken.into_iter().rev().collect::<Vec<_>>();
+        taken.iter().for_each(|&block| blocks.refs[block] = 1);
+        Ok(taken)
+    }
+
+    // one more holder for blocks already in use
+    pub fn retain(&self, blocks: &[usize]) {
+        let mut state = self.blocks.lock().unwrap();
+        for &block in blocks {
+            assert!(state.refs[block] > 0, "block {block} is free");
+            state.refs[block] += 1;
+        }
+    }
+
+    pub fn release(&self, blocks: &[usize]) {
+        let mut state = self.blocks.lock().unwrap();
+        for &block in blocks.iter().rev() {
+            state.refs[block] -= 1;
+            if state.refs[block] == 0 {
+                state.free.push(block);
+            }
+        }
+    }
+}
+
+// What happens when a sequence outgrows a cache of fixed capacity
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    // the forward pass fails and the cache is left as it was
+    #[default]
+    Error,
+    // the oldest positions are discarded, at least `discard` at a time, and the keys of the rest
+    // re-rotated to the positions they move to
+    Shift {
+        discard: usize,
+    },
+    // StreamingLLM: the first `sinks` positions, which attention leans on, are kept, then the
+    // oldest positions after them are discarded as with Shift
+    Sinks {
+        sinks: usize,
+        discard: usize,
+    },
+}
+
+// A forward pass that does not fit a cache whose policy is Overflow::Error, or that would not
+// fit even after discarding everything the policy allows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContextOverflow {
+    pub len: usize, // positions in the cache
+    pub new: usize, // positions to add
+    pub capacity: usize,
+}
+
+impl std::fmt::Display for ContextOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} positions do not fit a cache of {} holding {}",
+            self.new, self.capacity, self.len
+        )
+    }
+}
+
+impl std::error::Error for ContextOverflow {}
+
+// Why a forward pass could not take its new positions; the caches are left as they were
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheError {
+    Overflow(ContextOverflow),
+    PoolExhausted(PoolExhausted),
+    // A policy that discards on a cache longer than the original context of dynamic rope
+    // scaling: its keys were rotated with frequencies that cannot be moved back.
+    DynamicRope {
+        capacity: usize,
+        original_max: usize,
+    },
+}
+
+impl From<ContextOverflow> for CacheError {
+    fn from(e: ContextOverflow) -> Self {
+        CacheError::Overflow(e)
+    }
+}
+
+impl From<PoolExhausted> for CacheError {
+    fn from(e: PoolExhausted) -> Self {
+        CacheError::PoolExhausted(e)
+    }
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CacheError::Overflow(e) => e.fmt(f),
+            CacheError::PoolExhausted(e) => e.fmt(f),
+            CacheError::DynamicRope { capacity, original_max } => write!(
+                f,
+                "a cache of {capacity} positions past the {original_max} of dynamic rope scaling cannot discard"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
 pub struct KVCache<T> {
     k_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
     v_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
-    #[allow(unused)]
-    max_seq_len: usize,
+    max_seq_len: usize,      // rows stored per layer
+    window: Option<usize>,   // sliding window: position p lives in row p % max_seq_len
+    pages: Option<Pages<T>>, // paged: positions live in blocks of a pool, k_cache and v_cache are empty
+    int8: Option<Int8Cache>, // int8: positions are stored quantized, k_cache and v_cache are empty
+    overflow: Overflow,
     dim: usize,
     length: usize, // length of the current sequence
 }
 
-impl<T: Default + Copy> KVCache<T> {
+// int8 keys and values with a scale per position and kv head: (dqkv + 4) bytes a head instead of 4 dqkv
+#[derive(Clone)]
+struct Int8Cache {
+    k: Vec<Tensor<i8>>, // (max_seq_len, n_kv_head * dqkv) x layers
+    v: Vec<Tensor<i8>>,
+    k_scale: Vec<Tensor<f32>>, // (max_seq_len, n_kv_head) x layers
+    v_scale: Vec<Tensor<f32>>,
+    n_kv_h: usize,
+}
+
+// the blocks of a paged cache, in position order
+struct Pages<T> {
+    pool: Arc<BlockPool<T>>,
+    table: Vec<usize>,
+}
+
+impl<T: Default + Copy + Send + Sync + 'static> KVCache<T> {
     pub fn new(n_layers: usize, max_seq_len: usize, dim: usize, init_len: usize) -> Self {
         KVCache {
             k_cache: (0..n_layers)
@@ -19,25 +313,1015 @@ impl<T: Default + Copy> KVCache<T> {
             v_cache: (0..n_layers)
                 .map(|_| Tensor::default(&vec![max_seq_len, dim]))
                 .collect(),
-            max_seq_len: max_seq_len,
-            dim: dim,
+            max_seq_len,
+            window: None,
+            pages: None,
+            int8: None,
+            overflow: Overflow::Error,
+            dim,
             length: init_len,
         }
     }
 
+    // A ring buffer for sliding-window attention: only the last `window` positions are kept,
+    // so memory does not grow with the sequence.
+    pub fn new_window(n_layers: usize, window: usize, dim: usize) -> Self {
+        KVCache {
+            window: Some(window),
+            ..Self::new(n_layers, window, dim, 0)
+        }
+    }
+
+    // Positions in blocks taken from `pool` as the sequence grows and given back when the cache is
+    // dropped. Read with `append_blocks`.
+    pub fn new_paged(pool: &Arc<BlockPool<T>>) -> Self {
+        KVCache {
+            k_cache: Vec::new(),
+            v_cache: Vec::new(),
+            max_seq_len: usize::MAX,
+            window: None,
+            pages: Some(Pages {
+                pool: pool.clone(),
+                table: Vec::new(),
+            }),
+            int8: None,
+            overflow: Overflow::Error,
+            dim: pool.dim,
+            length: 0,
+        }
+    }
+
+    // A paged cache starting with positions already computed by another cache of the pool: the
+    // whole `blocks`, which are shared, never written to, until both caches are dropped.
+    pub fn new_paged_from(pool: &Arc<BlockPool<T>>, blocks: &[usize]) -> Self {
+        pool.retain(blocks);
+        let mut cache = Self::new_paged(pool);
+        cache.pages.as_mut().unwrap().table = blocks.to_vec();
+        cache.length = blocks.len() * pool.block_size;
+        cache
+    }
+
+    #[allow(unused)]
     pub fn k_cache(&mut self, layer: usize, start: usize) -> Tensor<T> {
+        assert!(
+            self.window.is_none(),
+            "a ring buffer has no contiguous range of positions"
+        );
         self.k_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
     }
 
+    #[allow(unused)]
     pub fn v_cache(&mut self, layer: usize, start: usize) -> Tensor<T> {
+        assert!(
+            self.window.is_none(),
+            "a ring buffer has no contiguous range of positions"
+        );
         self.v_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
     }
 
-    pub fn increment(&mut self, seq_len: usize){
+    // Store the (seq, dim) keys and values of the newest seq positions, already counted by
+    // `increment`, and return the keys and values they attend to, oldest first: every position
+    // so far, or with a window only the window - 1 positions before the new ones plus the new ones.
+    pub fn append(&mut self, layer: usize, k: &Tensor<T>, v: &Tensor<T>) -> (Tensor<T>, Tensor<T>) {
+        let seq_len = k.shape()[0];
+        assert_eq!(k.shape(), &vec![seq_len, self.dim]);
+        assert_eq!(v.shape(), k.shape());
+        assert!(
+            self.pages.is_none(),
+            "a paged cache is read through its block table, see append_blocks"
+        );
+        assert!(
+            self.int8.is_none(),
+            "an int8 cache is read quantized, see append_int8"
+        );
+        let past = self.length - seq_len;
+        let Some(window) = self.window else {
+            let range = past * self.dim..self.length * self.dim;
+            unshare(&mut self.k_cache[layer], past);
+            unshare(&mut self.v_cache[layer], past);
+            unsafe { self.k_cache[layer].data_mut()[range.clone()].copy_from_slice(k.data()) };
+            unsafe { self.v_cache[layer].data_mut()[range].copy_from_slice(v.data()) };
+            return (self.k_cache(layer, 0), self.v_cache(layer, 0));
+        };
+
+        let kept = past.min(window - 1);
+        let (dim, rows) = (self.dim, self.max_seq_len);
+        // copied before the new positions overwrite the oldest ones
+        let visible = |cache: &Tensor<T>, new: &Tensor<T>| {
+            let mut visible = Vec::with_capacity((kept + seq_len) * dim);
+            for p in past - kept..past {
+                visible.extend_from_slice(&cache.data()[p % rows * dim..][..dim]);
+            }
+            visible.extend_from_slice(new.data());
+            Tensor::new(visible, &vec![kept + seq_len, dim])
+        };
+        let (k_visible, v_visible) = (
+            visible(&self.k_cache[layer], k),
+            visible(&self.v_cache[layer], v),
+        );
+        self.write_ring(layer, k, v);
+        (k_visible, v_visible)
+    }
+
+    // `append` for a ring buffer whose rows hold both the window - 1 positions of history and
+    // the new ones, as they do one position at a time: the rows are read in place, wrapping
+    // around instead of being copied.
+    pub fn append_ring(&mut self, layer: usize, k: &Tensor<T>, v: &Tensor<T>) -> Paged<'_, T> {
+        let seq_len = k.shape()[0];
+        assert_eq!(k.shape(), &vec![seq_len, self.dim]);
+        assert_eq!(v.shape(), k.shape());
+        assert!(
+            self.ring_fits(seq_len),
+            "the new positions overwrite ones they attend to, see append"
+        );
+        let past = self.length - seq_len;
+        let kept = past.min(self.window.unwrap() - 1);
+        self.write_ring(layer, k, v);
+        let (k, v) = (self.k_cache[layer].data(), self.v_cache[layer].data());
+        let rows = self.max_seq_len;
+        Paged {
+            k: vec![k],
+            v: vec![v],
+            block_size: rows,
+            start: (past - kept) % rows,
+            len: kept + seq_len,
+        }
+    }
+
+    // whether `append_ring` can read the last seq_len positions and their history in place
+    fn ring_fits(&self, seq_len: usize) -> bool {
+        let Some(window) = self.window else {
+            return false;
+        };
+        (self.length - seq_len).min(window - 1) + seq_len <= self.max_seq_len
+    }
+
+    // write the newest positions of a ring buffer; only the newest max_seq_len of them survive
+    fn write_ring(&mut self, layer: usize, k: &Tensor<T>, v: &Tensor<T>) {
+        let (dim, rows, seq_len) = (self.dim, self.max_seq_len, k.shape()[0]);
+        let past = self.length - seq_len;
+        for (cache, new) in [(&mut self.k_cache[layer], k), (&mut self.v_cache[layer], v)] {
+            unshare(cache, past);
+            let data = unsafe { cache.data_mut() };
+            for p in self.length.saturating_sub(rows).max(past)..self.length {
+                data[p % rows * dim..][..dim]
+                    .copy_from_slice(&new.data()[(p - past) * dim..][..dim]);
+            }
+        }
+    }
+
+    // `append` for a paged cache: the keys and values are left in the blocks of the pool
+    pub fn append_blocks(&mut self, layer: usize, k: &Tensor<T>, v: &Tensor<T>) -> Paged<'_, T> {
+        let seq_len = k.shape()[0];
+        assert_eq!(k.shape(), &vec![seq_len, self.dim]);
+        assert_eq!(v.shape(), k.shape());
+        let pages = self.pages.as_ref().expect("not a paged cache");
+        let (pool, dim, block_size) = (&*pages.pool, self.dim, pages.pool.block_size);
+        let past = self.length - seq_len;
+        let mut p = past;
+        while p < self.length {
+            // the new positions in p's block
+            let end = ((p / block_size + 1) * block_size).min(self.length);
+            let rows = (p - past) * dim..(end - past) * dim;
+            pool.write(
+                layer,
+                pages.table[p / block_size],
+                p % block_size,
+                &k.data()[rows.clone()],
+                &v.data()[rows],
+            );
+            p = end;
+        }
+        let (k, v) = pages
+            .table
+            .iter()
+            .map(|&block| pool.block(layer, block))
+            .unzip();
+        Paged {
+            k,
+            v,
+            block_size,
+            start: 0,
+            len: self.length,
+        }
+    }
+
+    // the pool of a paged cache
+    pub fn pool(&self) -> Option<&Arc<BlockPool<T>>> {
+        self.pages.as_ref().map(|pages| &pages.pool)
+    }
+
+    // free blocks `increment(new)` takes from the pool of a paged cache, 0 for other caches
+    pub fn blocks_needed(&self, new: usize) -> usize {
+        let Some(pages) = &self.pages else { return 0 };
+        let (pool, past) = (&pages.pool, self.length);
+        // a copy of a shared, partly filled last block
+        let copy = !past.is_multiple_of(pool.block_size)
+            && new > 0
+            && pool.ref_count(pages.table[past / pool.block_size]) > 1;
+        pool.blocks_for(past + new) - pages.table.len() + copy as usize
+    }
+
+    // the block table of a paged cache
+    pub fn blocks(&self) -> Option<&[usize]> {
+        self.pages.as_ref().map(|pages| &pages.table[..])
+    }
+
+    // Another cache continuing from the same positions, e.g. for sampling several answers. The
+    // two share what they hold until one of them writes to it: a paged cache then copies the
+    // block written to, other caches their buffer of the layer.
+    #[allow(unused)]
+    pub fn fork(&self) -> Self {
+        let pages = self.pages.as_ref().map(|pages| {
+            pages.pool.retain(&pages.table);
+            Pages {
+                pool: pages.pool.clone(),
+                table: pages.table.clone(),
+            }
+        });
+        KVCache {
+            k_cache: self.k_cache.clone(),
+            v_cache: self.v_cache.clone(),
+            max_seq_len: self.max_seq_len,
+            window: self.window,
+            pages,
+            int8: self.int8.clone(),
+            overflow: self.overflow,
+            dim: self.dim,
+            length: self.length,
+        }
+    }
+
+    // Roll back to the first `len` positions, e.g. to drop rejected draft tokens or an answer to
+    // be generated again
+    #[allow(unused)]
+    pub fn truncate(&mut self, len: usize) {
+        assert!(
+            len <= self.length,
+            "cannot truncate {} positions to {len}",
+            self.length
+        );
+        if let Some(window) = self.window {
+            // the positions before len attention needs must not have been overwritten
+            let rollback = self.max_seq_len + 1 - window;
+            assert!(
+                self.length - len <= rollback,
+                "a ring buffer rolls back at most {rollback} positions"
+            );
+        }
+        if let Some(pages) = &mut self.pages {
+            let keep = pages.pool.blocks_for(len);
+            pages.pool.release(&pages.table[keep..]);
+            pages.table.truncate(keep);
+        }
+        self.length = len;
+    }
+
+    #[allow(unused)]
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    // positions the cache can hold, None if it never fills up: a ring buffer overwrites the
+    // oldest, a paged cache takes blocks as long as the pool has them
+    pub fn capacity(&self) -> Option<usize> {
+        match (self.window, &self.pages) {
+            (None, None) => Some(self.max_seq_len),
+            _ => None,
+        }
+    }
+
+    #[allow(unused)]
+    pub fn window(&self) -> Option<usize> {
+        self.window
+    }
+
+    // Count seq_len new positions, written next by `store`. A paged cache takes the blocks for
+    // them from its pool; if the pool does not have them the sequence is left as it was.
+    pub fn increment(&mut self, seq_len: usize) -> Result<(), PoolExhausted> {
+        let past = self.length;
+        if let Some(pages) = &mut self.pages {
+            let (pool, block_size) = (&pages.pool, pages.pool.block_size);
+            // the partly filled last block is written to next, not shared by then; the copy holds
+            // the same positions, so it stays even if the new blocks cannot be had
+            if !past.is_multiple_of(block_size) && seq_len > 0 {
+                let last = &mut pages.table[past / block_size];
+                *last = pool.make_unique(*last)?;
+            }
+            let new = pool.alloc(pool.blocks_for(past + seq_len) - pages.table.len())?;
+            pages.table.extend(new);
+        }
         self.length += seq_len;
+        Ok(())
     }
 
     pub fn len(&self) -> usize {
         self.length
     }
+
+    fn n_layers(&self) -> usize {
+        match (&self.pages, &self.int8) {
+            (Some(pages), _) => pages.pool.k.len(),
+            (None, Some(int8)) => int8.k.len(),
+            (None, None) => self.k_cache.len(),
+        }
+    }
+
+    // the positions whose rows are still stored: all of them unless a ring buffer wrapped around
+    fn held(&self) -> Range<usize> {
+        match self.window {
+            Some(_) => self.length.saturating_sub(self.max_seq_len)..self.length,
+            None => 0..self.length,
+        }
+    }
+
+    // the stored key and value of position p in a layer
+    fn position(&self, layer: usize, p: usize) -> (&[T], &[T]) {
+        let dim = self.dim;
+        let (k, v, row) = match (&self.pages, self.window) {
+            (Some(pages), _) => {
+                let (k, v) = pages
+                    .pool
+                    .block(layer, pages.table[p / pages.pool.block_size]);
+                (k, v, p % pages.pool.block_size)
+            }
+            (None, Some(_)) => (
+                self.k_cache[layer].data(),
+                self.v_cache[layer].data(),
+                p % self.max_seq_len,
+            ),
+            (None, None) => (self.k_cache[layer].data(), self.v_cache[layer].data(), p),
+        };
+        (&k[row * dim..][..dim], &v[row * dim..][..dim])
+    }
+}
+
+impl KVCache<f32> {
+    // Keys and values stored as int8 with a scale per position and kv head, which attention
+    // reads without converting them back. Read with `append_int8`.
+    pub fn new_int8(n_layers: usize, max_seq_len: usize, n_kv_h: usize, dqkv: usize) -> Self {
+        let dim = n_kv_h * dqkv;
+        let int8 = Int8Cache {
+            k: (0..n_layers)
+                .map(|_| Tensor::default(&vec![max_seq_len, dim]))
+                .collect(),
+            v: (0..n_layers)
+                .map(|_| Tensor::default(&vec![max_seq_len, dim]))
+                .collect(),
+            k_scale: (0..n_layers)
+                .map(|_| Tensor::default(&vec![max_seq_len, n_kv_h]))
+                .collect(),
+            v_scale: (0..n_layers)
+                .map(|_| Tensor::default(&vec![max_seq_len, n_kv_h]))
+                .collect(),
+            n_kv_h,
+        };
+        KVCache {
+            k_cache: Vec::new(),
+            v_cache: Vec::new(),
+            max_seq_len,
+            window: None,
+            pages: None,
+            int8: Some(int8),
+            overflow: Overflow::Error,
+            dim,
+            length: 0,
+        }
+    }
+
+    // `append` for an int8 cache: every position so far, quantized
+    pub fn append_int8(&mut self, layer: usize, k: &Tensor<f32>, v: &Tensor<f32>) -> Int8 {
+        let seq_len = k.shape()[0];
+        assert_eq!(k.shape(), &vec![seq_len, self.dim]);
+        assert_eq!(v.shape(), k.shape());
+        let int8 = self.int8.as_mut().expect("not an int8 cache");
+        let (n_kv_h, dim, past) = (int8.n_kv_h, self.dim, self.length - seq_len);
+        let dqkv = dim / n_kv_h;
+        for (q, scale, new) in [
+            (&mut int8.k[layer], &mut int8.k_scale[layer], k),
+            (&mut int8.v[layer], &mut int8.v_scale[layer], v),
+        ] {
+            unshare(q, past);
+            unshare(scale, past);
+            let (q, scale) = unsafe { (q.data_mut(), scale.data_mut()) };
+            let rows = new.data().chunks_exact(dqkv).enumerate();
+            for (i, row) in rows.map(|(i, row)| (past * n_kv_h + i, row)) {
+                scale[i] = quant::quantize_i8(row, &mut q[i * dqkv..][..dqkv]);
+            }
+        }
+        let len = self.length;
+        Int8 {
+            k: int8.k[layer].slice(0, &vec![len, dim]),
+            v: int8.v[layer].slice(0, &vec![len, dim]),
+            k_scale: int8.k_scale[layer].slice(0, &vec![len, n_kv_h]),
+            v_scale: int8.v_scale[layer].slice(0, &vec![len, n_kv_h]),
+        }
+    }
+
+    // Forget `count` positions from `start` on. The positions after them move down by count; their
+    // keys, an (n, dim) view for every layer, are passed to `rerotate` to be moved as well.
+    pub fn discard(
+        &mut self,
+        start: usize,
+        count: usize,
+        mut rerotate: impl FnMut(&mut Tensor<f32>),
+    ) {
+        assert!(
+            self.capacity().is_some(),
+            "only caches of fixed capacity discard positions"
+        );
+        assert!(start + count <= self.length);
+        let (dim, moved) = (self.dim, self.length - start - count);
+        let rows = start + count..self.length;
+        match &mut self.int8 {
+            None => {
+                for (k, v) in self.k_cache.iter_mut().zip(&mut self.v_cache) {
+                    unshare(k, self.length);
+                    unshare(v, self.length);
+                    move_rows(k, rows.clone(), start, dim);
+                    move_rows(v, rows.clone(), start, dim);
+                    rerotate(&mut k.slice(start * dim, &vec![moved, dim]));
+                }
+            }
+            Some(int8) => {
+                let (n_kv_h, dqkv) = (int8.n_kv_h, dim / int8.n_kv_h);
+                let layers = int8
+                    .k
+                    .iter_mut()
+                    .zip(&mut int8.v)
+                    .zip(&mut int8.k_scale)
+                    .zip(&mut int8.v_scale);
+                for (((k, v), k_scale), v_scale) in layers {
+                    unshare(k, self.length);
+                    unshare(v, self.length);
+                    unshare(k_scale, self.length);
+                    unshare(v_scale, self.length);
+                    move_rows(k, rows.clone(), start, dim);
+                    move_rows(v, rows.clone(), start, dim);
+                    move_rows(k_scale, rows.clone(), start, n_kv_h);
+                    move_rows(v_scale, rows.clone(), start, n_kv_h);
+                    // quantized again after rotating
+                    let mut k = k.slice(start * dim, &vec![moved, dim]);
+                    let mut k_scale = k_scale.slice(start * n_kv_h, &vec![moved, n_kv_h]);
+                    let rows = k.data().chunks_exact(dqkv).zip(k_scale.data());
+                    let data = rows
+                        .flat_map(|(row, &d)| row.iter().map(move |&x| x as f32 * d))
+                        .collect();
+                    let mut keys = Tensor::new(data, &vec![moved, dim]);
+                    rerotate(&mut keys);
+                    let (q, scales) = unsafe { (k.data_mut(), k_scale.data_mut()) };
+                    for (i, row) in keys.data().chunks_exact(dqkv).enumerate() {
+                        scales[i] = quant::quantize_i8(row, &mut q[i * dqkv..][..dqkv]);
+                    }
+                }
+            }
+        }
+        self.length -= count;
+    }
+
+    // Write the stored positions to a safetensors file, as f32 or int8 rows per layer, along with
+    // the sequence length and the caller's `metadata`, e.g. to resume the sequence in another
+    // process with `restore`.
+    pub fn save(&self, path: &Path, mut metadata: HashMap<String, String>) -> io::Result<()> {
+        let held = self.held();
+        let mut tensors = Vec::new(); // (name, dtype, shape, bytes)
+        for layer in 0..self.n_layers() {
+            match &self.int8 {
+                Some(int8) => {
+                    let n_kv_h = int8.n_kv_h;
+                    for (name, q, scale) in [
+                        ("k", &int8.k[layer], &int8.k_scale[layer]),
+                        ("v", &int8.v[layer], &int8.v_scale[layer]),
+                    ] {
+                        let q = q.data()[held.start * self.dim..held.end * self.dim]
+                            .iter()
+                            .map(|&x| x as u8)
+                            .collect();
+                        let scale =
+                            f32_bytes(&scale.data()[held.start * n_kv_h..held.end * n_kv_h]);
+                        tensors.push((
+                            format!("{name}.{layer}"),
+                            Dtype::I8,
+                            vec![held.len(), self.dim],
+                            q,
+                        ));
+                        tensors.push((
+                            format!("{name}_scale.{layer}"),
+                            Dtype::F32,
+                            vec![held.len(), n_kv_h],
+                            scale,
+                        ));
+                    }
+                }
+                None => {
+                    let (k, v): (Vec<_>, Vec<_>) =
+                        held.clone().map(|p| self.position(layer, p)).unzip();
+                    for (name, rows) in [("k", k), ("v", v)] {
+                        let data = rows.concat();
+                        tensors.push((
+                            format!("{name}.{layer}"),
+                            Dtype::F32,
+                            vec![held.len(), self.dim],
+                            f32_bytes(&data),
+                        ));
+                    }
+                }
+            }
+        }
+        metadata.insert("length".to_string(), self.length.to_string());
+        let views = tensors.iter().map(|(name, dtype, shape, bytes)| {
+            (name, TensorView::new(*dtype, shape.clone(), bytes).unwrap())
+        });
+        safetensors::serialize_to_file(views, &Some(metadata), path)
+            .map_err(|e| invalid_data(format!("{e:?}")))
+    }
+
+    // Fill this new, empty cache from a file written by `save`, whatever kind of cache wrote it:
+    // rows are converted between f32 and int8 as needed. A ring buffer that wrapped around can
+    // only be restored into another ring buffer. Returns the caller's metadata.
+    pub fn restore(mut self, path: &Path) -> io::Result<(Self, HashMap<String, String>)> {
+        assert_eq!(self.length, 0, "positions are restored into an empty cache");
+        let buffer = std::fs::read(path)?;
+        let (_, header) =
+            SafeTensors::read_metadata(&buffer).map_err(|e| invalid_data(format!("{e:?}")))?;
+        let file = SafeTensors::deserialize(&buffer).map_err(|e| invalid_data(format!("{e:?}")))?;
+        let mut metadata = header.metadata().clone().unwrap_or_default();
+        let length = metadata
+            .remove("length")
+            .and_then(|len| len.parse::<usize>().ok());
+        let length =
+            length.ok_or_else(|| invalid_data("no sequence length in the file".to_string()))?;
+        let n_layers = self.n_layers();
+        let layers = (0..)
+            .take_while(|layer| file.tensor(&format!("k.{layer}")).is_ok())
+            .count();
+        if layers != n_layers {
+            return Err(invalid_data(format!(
+                "the file is of a cache of {layers} layers, not {n_layers}"
+            )));
+        }
+
+        // the positions this cache stores, which the file must have
+        let needed = match (self.window, self.capacity()) {
+            (Some(_), _) => length.min(self.max_seq_len),
+            (None, Some(capacity)) if length > capacity => {
+                return Err(invalid_data(format!(
+                    "{length} positions do not fit a cache of {capacity}"
+                )));
+            }
+            _ => length,
+        };
+        let held = match file.tensor("k.0") {
+            Ok(k) => k.shape()[0],
+            Err(_) => needed, // no layers
+        };
+        if held < needed || held > length {
+            return Err(invalid_data(format!(
+                "the file holds the last {held} of {length} positions, the cache needs {needed}"
+            )));
+        }
+        // every layer is read before the cache takes its positions
+        let rows = (0..n_layers)
+            .map(|layer| {
+                let k = read_rows(&file, &format!("k.{layer}"), self.dim, held - needed)?;
+                let v = read_rows(&file, &format!("v.{layer}"), self.dim, held - needed)?;
+                Ok((k, v))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        self.length = length - needed;
+        self.increment(needed).map_err(io::Error::other)?;
+        for (layer, (k, v)) in rows.iter().enumerate() {
+            self.store(layer, k, v);
+        }
+        Ok((self, metadata))
+    }
+
+    // Store the keys and values of the newest positions, see `increment`, and return what
+    // attention reads for them in the cache's own representation.
+    pub fn store(&mut self, layer: usize, k: &Tensor<f32>, v: &Tensor<f32>) -> Cached<'_> {
+        if self.pages.is_some() {
+            Cached::Paged(self.append_blocks(layer, k, v))
+        } else if self.int8.is_some() {
+            Cached::Int8(self.append_int8(layer, k, v))
+        } else if self.ring_fits(k.shape()[0]) {
+            Cached::Paged(self.append_ring(layer, k, v))
+        } else {
+            let (k, v) = self.append(layer, k, v);
+            Cached::Rows(k, v)
+        }
+    }
+}
+
+// The (held - skip, dim) f32 rows of a saved `name.layer` tensor, after skipping the first `skip`:
+// as stored, or dequantized with the `name_scale.layer` scales.
+fn read_rows(file: &SafeTensors, name: &str, dim: usize, skip: usize) -> io::Result<Tensor<f32>> {
+    let tensor = file
+        .tensor(name)
+        .map_err(|e| invalid_data(format!("{name}: {e:?}")))?;
+    let (shape, bytes) = (tensor.shape(), tensor.data());
+    if shape.len() != 2 || shape[1] != dim {
+        return Err(invalid_data(format!(
+            "{name} is {shape:?}, the cache stores {dim} values a position"
+        )));
+    }
+    let rows = shape[0] - skip;
+    let data = match tensor.dtype() {
+        Dtype::F32 => bytes[skip * dim * 4..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        Dtype::I8 => {
+            let (prefix, layer) = name.split_once('.').unwrap();
+            let scales = read_scales(file, &format!("{prefix}_scale.{layer}"), shape[0])?;
+            let n_h = scales.len() / shape[0];
+            let dqkv = dim / n_h;
+            let q = bytes[skip * dim..].iter().map(|&x| x as i8);
+            q.enumerate()
+                .map(|(i, x)| x as f32 * scales[skip * n_h + i / dqkv])
+                .collect()
+        }
+        dtype => return Err(invalid_data(format!("{name} is {dtype:?}"))),
+    };
+    Ok(Tensor::new(data, &vec![rows, dim]))
+}
+
+// the (rows, n_kv_h) scales of a saved int8 tensor
+fn read_scales(file: &SafeTensors, name: &str, rows: usize) -> io::Result<Vec<f32>> {
+    let tensor = file
+        .tensor(name)
+        .map_err(|e| invalid_data(format!("{name}: {e:?}")))?;
+    if tensor.dtype() != Dtype::F32 || tensor.shape().len() != 2 || tensor.shape()[0] != rows {
+        return Err(invalid_data(format!(
+            "{name} is {:?} {:?}",
+            tensor.dtype(),
+            tensor.shape()
+        )));
+    }
+    Ok(tensor
+        .data()
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+fn f32_bytes(x: &[f32]) -> Vec<u8> {
+    x.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// A private copy of a (rows, width) buffer shared with a fork of the cache, before writing to
+// it. Only the rows of the first `used` positions are copied, the others start zeroed.
+fn unshare<T: Copy + Default + Send + Sync + 'static>(t: &mut Tensor<T>, used: usize) {
+    if t.is_shared() {
+        let width = t.size() / t.shape()[0];
+        let copied = used.min(t.shape()[0]) * width;
+        let mut data = Vec::with_capacity(t.size());
+        data.extend_from_slice(&t.data()[..copied]);
+        data.resize(t.size(), T::default());
+        *t = Tensor::new(data, t.shape());
+    }
+}
+
+// move `rows` of a (rows, width) buffer down to row `to`
+fn move_rows<T: Copy + Default + Send + Sync + 'static>(
+    t: &mut Tensor<T>,
+    rows: Range<usize>,
+    to: usize,
+    width: usize,
+) {
+    let data = unsafe { t.data_mut() };
+    data.copy_within(rows.start * width..rows.end * width, to * width);
+}
+
+// keys and values of a sequence as attention reads them, see KVCache::store
+pub enum Cached<'a> {
+    Rows(Tensor<f32>, Tensor<f32>), // (visible_seq, n_kv_head * dqkv): every position so far, or the window
+    Paged(Paged<'a, f32>),
+    Int8(Int8),
+}
+
+// blocks go back to the pool with the cache
+impl<T> Drop for Pages<T> {
+    fn drop(&mut self) {
+        self.pool.release(&self.table);
+    }
+}
+
+#[test]
+fn test_ring_buffer() {
+    let (window, dim) = (4, 2);
+    let mut ring = KVCache::<f32>::new_window(1, window, dim);
+    let mut full = KVCache::<f32>::new(1, 64, dim, 0);
+    let rows = |positions: std::ops::Range<usize>| {
+        let data = positions
+            .clone()
+            .flat_map(|p| [p as f32, -(p as f32)])
+            .collect();
+        Tensor::new(data, &vec![positions.len(), dim])
+    };
+    // a prompt longer than the window, then single tokens, then a short chunk
+    let mut pos = 0;
+    for seq_len in [6, 1, 1, 3, 1] {
+        let new = rows(pos..pos + seq_len);
+        ring.increment(seq_len).unwrap();
+        full.increment(seq_len).unwrap();
+        let (k, v) = ring.append(0, &new, &new);
+        let (k_full, _) = full.append(0, &new, &new);
+        pos += seq_len;
+        // window - 1 positions of history, then the new ones
+        let first = (pos - seq_len).saturating_sub(window - 1);
+        assert_eq!(k.data(), rows(first..pos).data());
+        assert_eq!(v.data(), k.data());
+        assert_eq!(k_full.data(), rows(0..pos).data());
+    }
+    assert_eq!(ring.len(), 12);
+
+    // read in place one position at a time, wrapping around the rows
+    for pos in 12..18 {
+        let new = rows(pos..pos + 1);
+        ring.increment(1).unwrap();
+        let paged = ring.append_ring(0, &new, &new);
+        assert_eq!(paged.start, (pos + 1 - window) % window);
+        let (k, v) = paged.gather();
+        assert_eq!(k.data(), rows(pos + 1 - window..pos + 1).data());
+        assert_eq!(v.data(), k.data());
+    }
+}
+
+#[test]
+fn test_paged_cache() {
+    let (block_size, dim) = (4, 2);
+    let pool = Arc::new(BlockPool::<f32>::new(2, 8, block_size, dim));
+    let mut full = KVCache::<f32>::new(2, 64, dim, 0);
+    let mut a = KVCache::new_paged(&pool);
+    let mut b = KVCache::new_paged(&pool);
+    let rows = |positions: std::ops::Range<usize>, sign: f32| {
+        let data = positions
+            .clone()
+            .flat_map(|p| [p as f32, sign * p as f32])
+            .collect();
+        Tensor::new(data, &vec![positions.len(), dim])
+    };
+    // two sequences growing in turns, each taking blocks as it needs them
+    let mut pos = 0;
+    for seq_len in [6, 1, 3, 1] {
+        for cache in [&mut a, &mut b, &mut full] {
+            cache.increment(seq_len).unwrap();
+        }
+        let new = rows(pos..pos + seq_len, 1.);
+        let (k_full, _) = full.append(1, &new, &new);
+        let (k, v) = a
+            .append_blocks(1, &new, &rows(pos..pos + seq_len, -1.))
+            .gather();
+        assert_eq!(k.data(), k_full.data());
+        assert_eq!(v.data(), rows(0..pos + seq_len, -1.).data());
+        b.append_blocks(1, &rows(pos..pos + seq_len, 2.), &new);
+        pos += seq_len;
+    }
+    assert_eq!(a.pages.as_ref().unwrap().table, [0, 1, 4]);
+    assert_eq!(b.blocks().unwrap(), [2, 3, 5]);
+    assert_eq!(pool.num_free(), 2);
+    drop(a);
+    assert_eq!(pool.num_free(), 5);
+    // freed blocks are reused
+    let mut c = KVCache::new_paged(&pool);
+    c.increment(1).unwrap();
+    assert_eq!(c.pages.as_ref().unwrap().table, [0]);
+    // a pool running short is an error, and the cache is left as it was
+    assert_eq!(
+        c.increment(4 * 4 + 4),
+        Err(PoolExhausted { needed: 5, free: 4 })
+    );
+    assert_eq!(
+        (c.len(), c.blocks().unwrap(), pool.num_free()),
+        (1, &[0][..], 4)
+    );
+    c.increment(4 * 4 + 3).unwrap();
+    assert_eq!(pool.num_free(), 0);
+}
+
+#[test]
+fn test_discard() {
+    let (n_kv_h, dqkv) = (2, 2);
+    let dim = n_kv_h * dqkv;
+    let rows = |positions: std::ops::Range<usize>| {
+        let data = positions
+            .clone()
+            .flat_map(|p| (0..dim).map(move |i| (p * 10 + i) as f32))
+            .collect();
+        Tensor::new(data, &vec![positions.len(), dim])
+    };
+    let doubled = |k: &mut Tensor<f32>| unsafe { k.data_mut() }.iter_mut().for_each(|x| *x *= 2.);
+    // positions 2, 3 and 4 go, 5..8 move down and get their keys doubled
+    let expected_k = [
+        rows(0..2).data(),
+        &rows(5..8).data().iter().map(|x| x * 2.).collect::<Vec<_>>(),
+    ]
+    .concat();
+    let expected_v = [rows(0..2).data(), rows(5..8).data()].concat();
+
+    let mut cache = KVCache::<f32>::new(1, 8, dim, 0);
+    cache.increment(8).unwrap();
+    cache.append(0, &rows(0..8), &rows(0..8));
+    cache.discard(2, 3, doubled);
+    assert_eq!(cache.len(), 5);
+    assert_eq!(cache.k_cache(0, 0).data(), expected_k);
+    assert_eq!(cache.v_cache(0, 0).data(), expected_v);
+
+    // int8 keys are requantized after rotating
+    let mut cache = KVCache::new_int8(1, 8, n_kv_h, dqkv);
+    cache.increment(8).unwrap();
+    cache.append_int8(0, &rows(0..8), &rows(0..8));
+    cache.discard(2, 3, doubled);
+    let (k, v) = cache.append_int8(0, &rows(0..0), &rows(0..0)).dequantize();
+    let close = |a: &[f32], b: &[f32]| {
+        a.iter()
+            .zip(b)
+            .all(|(x, y)| (x - y).abs() <= y.abs() / 100. + 0.01)
+    };
+    assert!(close(k.data(), &expected_k) && close(v.data(), &expected_v));
+}
+
+#[test]
+fn test_fork_and_truncate() {
+    let (block_size, dim) = (4, 2);
+    let rows = |positions: std::ops::Range<usize>, sign: f32| {
+        let data = positions
+            .clone()
+            .flat_map(|p| [p as f32, sign * p as f32])
+            .collect();
+        Tensor::new(data, &vec![positions.len(), dim])
+    };
+
+    // dense: a fork keeps the prefix, then each branch writes its own buffer
+    let mut a = KVCache::<f32>::new(1, 16, dim, 0);
+    a.increment(6).unwrap();
+    a.append(0, &rows(0..6, 1.), &rows(0..6, 1.));
+    let mut b = a.fork();
+    a.increment(2).unwrap();
+    a.append(0, &rows(6..8, 1.), &rows(6..8, 1.));
+    b.increment(2).unwrap();
+    let (k, _) = b.append(0, &rows(6..8, -1.), &rows(6..8, -1.));
+    assert_eq!(
+        k.data(),
+        [rows(0..6, 1.).data(), rows(6..8, -1.).data()].concat()
+    );
+    assert_eq!(a.k_cache(0, 0).data(), rows(0..8, 1.).data());
+    // rejected positions are written over on the next append
+    a.truncate(5);
+    a.increment(1).unwrap();
+    let (k, _) = a.append(0, &rows(5..6, -1.), &rows(5..6, -1.));
+    assert_eq!(
+        k.data(),
+        [rows(0..5, 1.).data(), rows(5..6, -1.).data()].concat()
+    );
+    // a branch copies only the rows it holds
+    a.truncate(3);
+    let mut c = a.fork();
+    c.increment(1).unwrap();
+    c.append(0, &rows(3..4, -1.), &rows(3..4, -1.));
+    assert_eq!(
+        c.k_cache[0].data()[..4 * dim],
+        [rows(0..3, 1.).data(), rows(3..4, -1.).data()].concat()
+    );
+    assert!(c.k_cache[0].data()[4 * dim..].iter().all(|&x| x == 0.));
+    assert_eq!(
+        a.k_cache[0].data()[3 * dim..6 * dim],
+        [rows(3..5, 1.).data(), rows(5..6, -1.).data()].concat()
+    );
+
+    // paged: a fork shares the blocks, the partly filled one is copied when written to
+    let pool = Arc::new(BlockPool::<f32>::new(1, 8, block_size, dim));
+    let mut a = KVCache::new_paged(&pool);
+    a.increment(6).unwrap();
+    a.append_blocks(0, &rows(0..6, 1.), &rows(0..6, 1.));
+    let mut b = a.fork();
+    assert_eq!(
+        (pool.ref_count(0), pool.ref_count(1), pool.num_free()),
+        (2, 2, 6)
+    );
+    b.increment(1).unwrap();
+    let (k, _) = b
+        .append_blocks(0, &rows(6..7, -1.), &rows(6..7, -1.))
+        .gather();
+    assert_eq!(
+        k.data(),
+        [rows(0..6, 1.).data(), rows(6..7, -1.).data()].concat()
+    );
+    assert_eq!(b.blocks().unwrap(), [0, 2]);
+    a.increment(3).unwrap();
+    let (k, _) = a
+        .append_blocks(0, &rows(6..9, 1.), &rows(6..9, 1.))
+        .gather();
+    assert_eq!(k.data(), rows(0..9, 1.).data());
+    assert_eq!(a.blocks().unwrap(), [0, 1, 3]);
+    assert_eq!((pool.ref_count(0), pool.ref_count(1)), (2, 1));
+    // truncating gives back the blocks past the new length
+    a.truncate(4);
+    assert_eq!(a.blocks().unwrap(), [0]);
+    assert_eq!(pool.num_free(), 8 - 2);
+    drop(b);
+    a.truncate(0);
+    assert_eq!(pool.num_free(), 8);
+}
+
+#[test]
+fn test_save_restore() {
+    let (n_kv_h, dqkv) = (2, 2);
+    let dim = n_kv_h * dqkv;
+    let rows = |positions: std::ops::Range<usize>, sign: f32| {
+        let data = positions
+            .clone()
+            .flat_map(|p| (0..dim).map(move |i| sign * (p * 10 + i) as f32))
+            .collect();
+        Tensor::new(data, &vec![positions.len(), dim])
+    };
+    let path = std::env::temp_dir().join(format!("kvcache-{}.safetensors", std::process::id()));
+    let close = |a: &[f32], b: &[f32]| {
+        a.iter()
+            .zip(b)
+            .all(|(x, y)| (x - y).abs() <= y.abs() / 100. + 0.01)
+    };
+    let pool = Arc::new(BlockPool::<f32>::new(2, 8, 4, dim));
+    let (expected_k, expected_v) = (
+        [rows(0..6, 1.), rows(6..7, 1.)],
+        [rows(0..6, -1.), rows(6..7, -1.)],
+    );
+
+    // what a dense cache saved goes into a cache of every kind, with the caller's metadata
+    let mut dense = KVCache::<f32>::new(2, 8, dim, 0);
+    dense.increment(6).unwrap();
+    for layer in 0..2 {
+        dense.append(layer, &expected_k[0], &expected_v[0]);
+    }
+    dense
+        .save(
+            &path,
+            HashMap::from([("tokens".to_string(), "[1, 2]".to_string())]),
+        )
+        .unwrap();
+    let empty = [
+        KVCache::new(2, 8, dim, 0),
+        KVCache::new_paged(&pool),
+        KVCache::new_int8(2, 8, n_kv_h, dqkv),
+    ];
+    for cache in empty {
+        let (mut cache, metadata) = cache.restore(&path).unwrap();
+        assert_eq!(
+            metadata,
+            HashMap::from([("tokens".to_string(), "[1, 2]".to_string())])
+        );
+        cache.increment(1).unwrap();
+        let (k, v) = match cache.store(1, &expected_k[1], &expected_v[1]) {
+            Cached::Rows(k, v) => (k, v),
+            Cached::Paged(paged) => paged.gather(),
+            Cached::Int8(int8) => int8.dequantize(),
+        };
+        assert!(close(k.data(), rows(0..7, 1.).data()) && close(v.data(), rows(0..7, -1.).data()));
+    }
+    assert_eq!(pool.num_free(), 8);
+    // neither a cache of another number of layers nor a pool too short for the positions
+    assert!(KVCache::<f32>::new(0, 8, dim, 0).restore(&path).is_err());
+    let small = Arc::new(BlockPool::<f32>::new(2, 1, 4, dim));
+    assert!(KVCache::new_paged(&small).restore(&path).is_err());
+    assert_eq!(small.num_free(), 1);
+
+    // an int8 cache is saved quantized and read back the same
+    let mut int8 = KVCache::new_int8(2, 8, n_kv_h, dqkv);
+    int8.increment(6).unwrap();
+    let saved = int8
+        .append_int8(0, &expected_k[0], &expected_v[0])
+        .dequantize();
+    int8.save(&path, HashMap::new()).unwrap();
+    let (mut restored, _) = KVCache::new_int8(2, 8, n_kv_h, dqkv)
+        .restore(&path)
+        .unwrap();
+    let (k, v) = restored
+        .append_int8(0, &rows(0..0, 1.), &rows(0..0, 1.))
+        .dequantize();
+    assert_eq!((k.data(), v.data()), (saved.0.data(), saved.1.data()));
+
+    // a ring buffer that wrapped around only has the positions of the window
+    let mut ring = KVCache::<f32>::new_window(2, 4, dim);
+    ring.increment(6).unwrap();
+    ring.append(0, &expected_k[0], &expected_v[0]);
+    ring.save(&path, HashMap::new()).unwrap();
+    assert!(KVCache::<f32>::new(2, 8, dim, 0).restore(&path).is_err());
+    let (mut restored, _) = KVCache::<f32>::new_window(2, 4, dim)
+        .restore(&path)
+        .unwrap();
+    restored.increment(1).unwrap();
+    let (k, _) = restored.append(0, &expected_k[1], &expected_v[1]);
+    assert_eq!(k.data(), rows(3..7, 1.).data());
+    // nor does a sequence go into a cache too small for it
+    assert!(KVCache::<f32>::new(2, 4, dim, 0).restore(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
 }
@@ -1,28 +1,73 @@
+mod attention;
+mod backend;
 mod config;
+mod gemm;
 mod kvcache;
 mod model;
 mod operators;
 mod params;
+mod pool;
+mod prefix_cache;
+mod quant;
+mod rope;
+mod scheduler;
 mod tensor;
 
 use std::path::PathBuf;
 use tokenizers::Tokenizer;
 
 fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("quantize") {
+        return quantize(&args[1..]);
+    }
+
+    if let Some(n) = std::env::var("NUM_THREADS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+    {
+        operators::set_num_threads(n);
+    }
+
     let project_dir = env!("CARGO_MANIFEST_DIR");
-    let model_dir = PathBuf::from(project_dir).join("models").join("story");
-    let llama = model::Llama::<f32>::from_safetensors(&model_dir);
+    let model_dir = match args.first() {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(project_dir).join("models").join("story"),
+    };
+    let mut llama = model::Llama::<f32>::from_safetensors(&model_dir);
+    if let Some(chunk) = std::env::var("PREFILL_CHUNK")
+        .ok()
+        .and_then(|n| n.parse().ok())
+    {
+        llama = llama.with_prefill_chunk(chunk);
+    }
     let tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json")).unwrap();
     let input = "Once upon a time";
     let binding = tokenizer.encode(input, true).unwrap();
     let input_ids = binding.get_ids();
     print!("\n{}", input);
-    let output_ids = llama.generate(
-        input_ids,
-        500,
-        0.8,
-        30,
-        1.,
-    );
+    let output_ids = llama.generate(input_ids, 500, 0.8, 30, 1.);
     println!("{}", tokenizer.decode(&output_ids, true).unwrap());
 }
+
+// quantize <src_dir> <dst_dir> <q8_0|q4_0> [field ...]
+fn quantize(args: &[String]) {
+    let usage = "usage: quantize <src_dir> <dst_dir> <q8_0|q4_0> [field ...]";
+    let [src, dst, format, fields @ ..] = args else {
+        panic!("{usage}");
+    };
+    let format = quant::QuantFormat::parse(format).expect(usage);
+    let mut fields = fields.iter().map(String::as_str).collect::<Vec<_>>();
+    if fields.is_empty() {
+        fields = params::DEFAULT_QUANT_FIELDS.to_vec();
+    }
+    for field in &fields {
+        assert!(
+            params::MATRIX_FIELDS.contains(field),
+            "{field} is not one of {:?}",
+            params::MATRIX_FIELDS
+        );
+    }
+    params::quantize_safetensors(src.as_ref(), dst.as_ref(), &fields, format).unwrap();
+    println!("wrote {} weights of {fields:?} to {dst}", format.name());
+}
@@ -1,7 +1,13 @@
-use crate::tensor::Tensor;
+use crate::attention::Mask;
+use crate::gemm::{self, Isa};
+use crate::pool;
+use crate::rope::RopeLayout;
+use crate::tensor::{Float, Tensor};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 // get (row) vectors from a 2D table given a list of indices
-pub fn gather(y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<f32>) {
+pub fn gather<W: Float>(y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<W>) {
     let length = indices.size();
     let table_shape = table.shape();
     assert!(table_shape.len() == 2);
@@ -10,36 +16,57 @@ pub fn gather(y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<f32>) {
     for i in 0..length {
         let src = &table.data()[indices.data()[i] as usize * dim..][..dim];
         let dst = &mut unsafe { y.data_mut() }[i * dim..][..dim];
-        dst.copy_from_slice(src);
+        dst.iter_mut().zip(src).for_each(|(d, s)| *d = s.to_f32());
     }
 }
 
 // RoPE: Rotary Positional Embedding
-pub fn rope(y: &mut Tensor<f32>, start_pos: usize, theta: f32) {
+// y: (seq, n_heads, d), each head rotated by the angles of its position
+// cos, sin: (seq, r / 2) rows of the model's RotaryEmbedding tables for the positions of y;
+// only the first r <= d dimensions of a head are rotated, paired up according to `layout`
+pub fn rope(y: &mut Tensor<f32>, cos: &Tensor<f32>, sin: &Tensor<f32>, layout: RopeLayout) {
     let shape = y.shape();
     assert!(shape.len() == 3);
     let seq_len = shape[0];
     let n_heads = shape[1];
     let d = shape[2];
+    let half = cos.shape()[1];
+    assert!(
+        2 * half <= d,
+        "cannot rotate {} of {d} dimensions",
+        2 * half
+    );
+    assert_eq!(cos.shape(), &vec![seq_len, half]);
+    assert_eq!(sin.shape(), &vec![seq_len, half]);
+    // index of the two elements of pair i
+    let pair = |i: usize| match layout {
+        RopeLayout::Halves => (i, i + half),
+        RopeLayout::Interleaved => (2 * i, 2 * i + 1),
+    };
+    let (cos, sin) = (cos.data(), sin.data());
     let data = unsafe { y.data_mut() };
     for tok in 0..seq_len {
-        let pos = start_pos + tok;
+        let cos = &cos[tok * half..][..half];
+        let sin = &sin[tok * half..][..half];
         for head in 0..n_heads {
-            for i in 0..d / 2 {
-                let a = data[tok * n_heads * d + head * d + i];
-                let b = data[tok * n_heads * d + head * d + i + d / 2];
-                let freq = pos as f32 / theta.powf((i * 2) as f32 / d as f32);
-                let (sin, cos) = freq.sin_cos();
-                data[tok * n_heads * d + head * d + i] = a * cos - b * sin;
-                data[tok * n_heads * d + head * d + i + d / 2] = b * cos + a * sin;
+            let x = &mut data[(tok * n_heads + head) * d..][..d];
+            for i in 0..half {
+                let (i0, i1) = pair(i);
+                let (a, b) = (x[i0], x[i1]);
+                x[i0] = a * cos[i] - b * sin[i];
+                x[i1] = b * cos[i] + a * sin[i];
             }
         }
     }
 }
 
 // softmax(x) = exp(x - max) / sum(exp(x - max))
-// y = softmax(mask(x))
-pub fn masked_softmax(y: &mut Tensor<f32>) {
+// y = softmax(mask(x)), y: (..., seq, total_seq) scores of seq queries against total_seq keys
+pub fn masked_softmax(y: &mut Tensor<f32>, mask: Mask) {
+    mask.check_shape(
+        y.shape()[y.shape().len() - 2],
+        y.shape()[y.shape().len() - 1],
+    );
     let ndim = y.shape().len();
     assert!(ndim >= 2);
     let seq_len = y.shape()[ndim - 2];
@@ -49,31 +76,45 @@ pub fn masked_softmax(y: &mut Tensor<f32>) {
     for b in 0..batch {
         let base = b * seq_len * total_seq_len;
         for i in 0..seq_len {
-            let offset = base + i * total_seq_len;
-            let boundary = total_seq_len - seq_len + i + 1;
+            let row = &mut data[base + i * total_seq_len..][..total_seq_len];
+            let visible = mask.visible(i, seq_len, total_seq_len);
+            if !mask.is_range() {
+                for j in visible.clone() {
+                    if !mask.allows(i, j, total_seq_len) {
+                        row[j] = f32::NEG_INFINITY;
+                    }
+                }
+            }
 
-            let max = data[offset..offset + boundary]
+            let max = row[visible.clone()]
                 .iter()
-                .fold(data[offset], |a, b| a.max(*b));
+                .fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+            if max == f32::NEG_INFINITY {
+                // a row that may not attend to anything gets zeros
+                row.fill(0.0);
+                continue;
+            }
 
-            let sum = (0..boundary)
-                .map(|j| {
-                    let e = (data[offset + j] - max).exp();
-                    data[offset + j] = e;
-                    e
+            let sum = row[visible.clone()]
+                .iter_mut()
+                .map(|x| {
+                    *x = (*x - max).exp();
+                    *x
                 })
                 .sum::<f32>();
 
-            (0..boundary).for_each(|j| data[offset + j] /= sum);
-            (boundary..total_seq_len).for_each(|j| data[offset + j] = 0.0);
+            row[visible.clone()].iter_mut().for_each(|x| *x /= sum);
+            row[..visible.start].iter_mut().for_each(|x| *x = 0.0);
+            row[visible.end..].iter_mut().for_each(|x| *x = 0.0);
         }
     }
 }
 
-pub fn rms_norm(y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon: f32) {
+// w may be stored in 16 bits, the reduction is always done in f32
+pub fn rms_norm<W: Float>(y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<W>, epsilon: f32) {
     let len_x = x.size();
     let len_w = w.size();
-    assert!(len_x % len_w == 0);
+    assert!(len_x.is_multiple_of(len_w));
 
     let y_data = unsafe { y.data_mut() };
     let x_data = x.data();
@@ -94,7 +135,7 @@ pub fn rms_norm(y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon:
         let rms = (sum_sq / n as f32 + epsilon).sqrt();
 
         for j in 0..n {
-            y_data[off + j] = x_data[off + j] * w_data[j] / rms;
+            y_data[off + j] = x_data[off + j] * w_data[j].to_f32() / rms;
         }
     }
 
@@ -120,8 +161,41 @@ pub fn swiglu(y: &mut Tensor<f32>, x: &Tensor<f32>) {
 
 // C = beta * C + alpha * A @ B^T
 // hint: You don't need to do an explicit transpose of B
-// tensor里面没有实现transpose和stride，所以matmul实现2D就能满足需求了
-pub fn matmul_transb(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor<f32>, alpha: f32) {
+// A and B may be strided views (e.g. a single head or a transposed matrix), C must be packed
+// B is usually a weight and may be f16/bf16, products are accumulated in f32
+pub fn matmul_transb<W: Float>(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b: &Tensor<W>,
+    alpha: f32,
+) {
+    matmul_transb_threads(c, beta, a, b, alpha, num_threads());
+}
+
+// matmul_transb on an explicit number of threads; the result does not depend on `threads`
+pub fn matmul_transb_threads<W: Float>(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b: &Tensor<W>,
+    alpha: f32,
+    threads: usize,
+) {
+    matmul_transb_isa(c, beta, a, b, alpha, threads, gemm::isa());
+}
+
+// f32 operands with unit inner stride go through the blocked SIMD kernel of `isa`,
+// everything else (and Isa::Scalar) through the plain loop
+pub fn matmul_transb_isa<W: Float>(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b: &Tensor<W>,
+    alpha: f32,
+    threads: usize,
+    isa: Isa,
+) {
     let (m, k) = (a.shape()[0], a.shape()[1]);
     let (n, k_b) = (b.shape()[0], b.shape()[1]);
     assert_eq!(
@@ -130,22 +204,96 @@ pub fn matmul_transb(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor
     );
     assert_eq!(c.shape(), &[m, n], "C must have shape [m, n] where m is the number of rows in A and n is the number of rows in B");
 
-    let a_data = a.data();
-    let b_data = b.data();
-    let c_data = unsafe { c.data_mut() };
+    let (a_row, a_col) = (a.strides()[0], a.strides()[1]);
+    let (b_row, b_col) = (b.strides()[0], b.strides()[1]);
+    let a_data = a.strided_data();
+    let b_data = b.strided_data();
+
+    let simd_b = W::as_f32(b_data).filter(|_| isa != Isa::Scalar && a_col == 1 && b_col == 1);
 
     // C(i, j) -> A(i, indx) * B(j, indx)
-    for i in 0..m {
-        for j in 0..n {
-            let mut sum = 0.0;
-            for l in 0..k {
-                sum += a_data[i * k + l] * b_data[j * k + l];
+    par_tiles(c, k, threads, |c_tile, ldc, rows, cols| {
+        if let Some(b_f32) = simd_b {
+            return gemm::tile_f32(
+                isa, c_tile, ldc, rows, cols, beta, a_data, a_row, b_f32, b_row, k, alpha,
+            );
+        }
+        for (ti, i) in rows.enumerate() {
+            for (tj, j) in cols.clone().enumerate() {
+                let mut sum = 0.0;
+                for l in 0..k {
+                    sum += a_data[i * a_row + l * a_col] * b_data[j * b_row + l * b_col].to_f32();
+                }
+                let c_ij = &mut c_tile[ti * ldc + tj];
+                *c_ij = beta * *c_ij + alpha * sum;
             }
-            c_data[i * n + j] = beta * c_data[i * n + j] + alpha * sum;
         }
+    });
+}
+
+// 0 means one worker per available core
+static NUM_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+// Set the number of worker threads used by the matmul kernels.
+pub fn set_num_threads(n: usize) {
+    NUM_THREADS.store(n, Ordering::Relaxed);
+}
+
+pub fn num_threads() -> usize {
+    match NUM_THREADS.load(Ordering::Relaxed) {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+// below this many multiply-adds a matmul is not worth splitting across threads
+const PAR_MIN_WORK: usize = 1 << 16;
+
+// Split the (m, n) output C of a matmul with inner dimension k across threads.
+// `tile(c, ldc, rows, cols)` updates C[rows, cols] held in `c`, which starts at element
+// (rows.start, cols.start) and has row stride `ldc`. Every element is computed by exactly one
+// call with the same arithmetic, so results are identical for any number of threads.
+pub(crate) fn par_tiles<F>(c: &mut Tensor<f32>, k: usize, threads: usize, tile: F)
+where
+    F: Fn(&mut [f32], usize, Range<usize>, Range<usize>) + Sync,
+{
+    let (m, n) = (c.shape()[0], c.shape()[1]);
+    let c_data = unsafe { c.data_mut() };
+    let threads = threads.min(m * n * k / PAR_MIN_WORK).max(1);
+    if threads == 1 {
+        return tile(c_data, n, 0..m, 0..n);
     }
 
-    // todo!("实现 matmul_transb，计算前做一些必要的检查会帮助你后续调试");
+    if m >= threads {
+        // prefill: each thread owns a band of rows
+        let rows_per = m.div_ceil(threads);
+        let bands = c_data.chunks_mut(rows_per * n).enumerate().collect();
+        pool::map(bands, threads, |(t, band): (usize, &mut [f32])| {
+            let rows = t * rows_per..(t * rows_per + band.len() / n);
+            tile(band, n, rows, 0..n)
+        });
+    } else {
+        // decode: few rows, each row is split into bands of columns so that there are about
+        // `threads` bands in all
+        let cols_per = n.div_ceil(threads.div_ceil(m));
+        let bands = c_data
+            .chunks_mut(n)
+            .enumerate()
+            .flat_map(|(i, row)| {
+                row.chunks_mut(cols_per)
+                    .enumerate()
+                    .map(move |(t, band)| (i, t, band))
+            })
+            .collect();
+        pool::map(
+            bands,
+            threads,
+            |(i, t, band): (usize, usize, &mut [f32])| {
+                let cols = t * cols_per..t * cols_per + band.len();
+                tile(band, n, i..i + 1, cols)
+            },
+        );
+    }
 }
 
 // Dot product of two tensors (treated as vectors)
@@ -200,7 +348,7 @@ pub fn random_sample(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32)
         #[inline]
         fn from((i, p): (usize, &f32)) -> Self {
             Self {
-                val: p.clone(),
+                val: *p,
                 tok: i as _,
             }
         }
@@ -265,3 +413,120 @@ fn test_matmul_transb() {
         1e-3
     ));
 }
+
+#[test]
+fn test_matmul_transb_strided() {
+    // B given as the transposed view of a (k, n) matrix
+    let mut c = Tensor::<f32>::default(&vec![2, 2]);
+    let a = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let b = Tensor::<f32>::new(vec![1., 4., 2., 5., 3., 6.], &vec![3, 2]);
+    matmul_transb(&mut c, 0., &a, &b.transpose(0, 1), 1.);
+    assert!(c.close_to(
+        &Tensor::<f32>::new(vec![14., 32., 32., 77.], &vec![2, 2]),
+        1e-3
+    ));
+}
+
+#[test]
+fn test_half_weights() {
+    use half::{bf16, f16};
+    let to_bf16 = |v: &[f32]| v.iter().map(|&x| bf16::from_f32(x)).collect::<Vec<_>>();
+    let to_f16 = |v: &[f32]| v.iter().map(|&x| f16::from_f32(x)).collect::<Vec<_>>();
+
+    let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let a = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let b = Tensor::new(to_bf16(&[1., 2., 3., 4., 5., 6.]), &vec![2, 3]);
+    matmul_transb(&mut c, 1., &a, &b, 1.);
+    assert!(c.close_to(
+        &Tensor::<f32>::new(vec![15., 34., 35., 81.], &vec![2, 2]),
+        1e-3
+    ));
+
+    let mut y = Tensor::<f32>::default(&vec![2, 2]);
+    let x = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
+    let w = Tensor::new(to_f16(&[1., 2.]), &vec![2]);
+    rms_norm(&mut y, &x, &w, 1e-6);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(
+            vec![0.6324554, 2.5298216, 0.8485281, 2.2627416],
+            &vec![2, 2]
+        ),
+        1e-3
+    ));
+
+    let mut y = Tensor::<f32>::default(&vec![2, 2]);
+    let table = Tensor::new(to_bf16(&[0.5, 1.5, 2.5, 3.5]), &vec![2, 2]);
+    gather(&mut y, &Tensor::<u32>::new(vec![1, 0], &vec![2]), &table);
+    assert_eq!(y.data(), &[2.5, 3.5, 0.5, 1.5]);
+}
+
+#[test]
+fn test_matmul_transb_threads() {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(8);
+    // tall (row split) and short (column split) outputs
+    for (m, n, k) in [(130, 70, 65), (1, 2050, 129), (3, 1001, 67)] {
+        let a = Tensor::<f32>::new(
+            (0..m * k).map(|_| rng.gen_range(-1.0..1.0)).collect(),
+            &vec![m, k],
+        );
+        let b = Tensor::<f32>::new(
+            (0..n * k).map(|_| rng.gen_range(-1.0..1.0)).collect(),
+            &vec![n, k],
+        );
+        let c0 = Tensor::<f32>::new(
+            (0..m * n).map(|_| rng.gen_range(-1.0..1.0)).collect(),
+            &vec![m, n],
+        );
+        let mut serial = Tensor::<f32>::new(c0.data().to_vec(), &vec![m, n]);
+        matmul_transb_threads(&mut serial, 0.5, &a, &b, 1.5, 1);
+        for threads in [2, 3, 8] {
+            let mut c = Tensor::<f32>::new(c0.data().to_vec(), &vec![m, n]);
+            matmul_transb_threads(&mut c, 0.5, &a, &b, 1.5, threads);
+            assert_eq!(c.data(), serial.data(), "{m}x{n}x{k} on {threads} threads");
+        }
+    }
+}
+
+#[test]
+fn test_matmul_transb_simd() {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(9);
+    // shapes that leave partial micro-tiles, cache blocks and vector tails
+    for (m, n, k) in [
+        (1, 1, 1),
+        (5, 7, 3),
+        (13, 67, 37),
+        (70, 9, 129),
+        (1, 131, 300),
+        (66, 65, 17),
+    ] {
+        let a = Tensor::<f32>::new(
+            (0..m * k).map(|_| rng.gen_range(-1.0..1.0)).collect(),
+            &vec![m, k],
+        );
+        let b = Tensor::<f32>::new(
+            (0..n * k).map(|_| rng.gen_range(-1.0..1.0)).collect(),
+            &vec![n, k],
+        );
+        let c0 = (0..m * n)
+            .map(|_| rng.gen_range(-1.0..1.0))
+            .collect::<Vec<f32>>();
+        let mut scalar = Tensor::<f32>::new(c0.clone(), &vec![m, n]);
+        matmul_transb_isa(&mut scalar, 0.5, &a, &b, 1.5, 1, Isa::Scalar);
+        for isa in gemm::available() {
+            let mut c = Tensor::<f32>::new(c0.clone(), &vec![m, n]);
+            matmul_transb_isa(&mut c, 0.5, &a, &b, 1.5, 1, isa);
+            for (x, y) in c.data().iter().zip(scalar.data()) {
+                assert!(
+                    (x - y).abs() <= 1e-4 * (k as f32).sqrt(),
+                    "{isa:?} {m}x{n}x{k}: {x} vs {y}"
+                );
+            }
+            // splitting the work between threads must not change a single bit
+            let mut par = Tensor::<f32>::new(c0.clone(), &vec![m, n]);
+            matmul_transb_isa(&mut par, 0.5, &a, &b, 1.5, 3, isa);
+            assert_eq!(par.data(), c.data());
+        }
+    }
+}
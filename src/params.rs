@@ -1,72 +1,412 @@
 use crate::config::LlamaConfigJson;
-use crate::tensor::Tensor;
-use safetensors::SafeTensors;
+use crate::quant::{QTensor, QuantFormat, Weight, QK};
+use crate::tensor::{Float, Tensor};
+use half::{bf16, f16};
+use memmap2::Mmap;
+use safetensors::tensor::{Metadata, TensorInfo, TensorView};
+use safetensors::{Dtype, SafeTensors};
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
 pub struct LLamaParams<T> {
     // token_id to embedding lookup table
-    pub embedding_table: Tensor<T>, // (vocab_size, dim)
+    pub embedding_table: Weight<T>, // (vocab_size, dim)
     // decoder layer
     pub rms_att_w: Vec<Tensor<T>>, // (hidden_size, ) x layers
-    pub wq: Vec<Tensor<T>>,        // (n_heads * head_size, hidden_size) x layers
-    pub wk: Vec<Tensor<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
-    pub wv: Vec<Tensor<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
-    pub wo: Vec<Tensor<T>>,        // (hidden_size, n_heads * head_size) x layers
+    pub wq: Vec<Weight<T>>,        // (n_heads * head_size, hidden_size) x layers
+    pub wk: Vec<Weight<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
+    pub wv: Vec<Weight<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
+    pub wo: Vec<Weight<T>>,        // (hidden_size, n_heads * head_size) x layers
     // ffn layer
     pub rms_ffn_w: Vec<Tensor<T>>, // (hidden_size, ) x layers
-    pub w_up: Vec<Tensor<T>>,      // (intermediate_size, hidden_size) x layers
-    pub w_gate: Vec<Tensor<T>>,    // (intermediate_size, hidden_size) x layers
-    pub w_down: Vec<Tensor<T>>,    // (hidden_size, intermediate_size) x layers
+    pub w_up: Vec<Weight<T>>,      // (intermediate_size, hidden_size) x layers
+    pub w_gate: Vec<Weight<T>>,    // (intermediate_size, hidden_size) x layers
+    pub w_down: Vec<Weight<T>>,    // (hidden_size, intermediate_size) x layers
     // output
     pub rms_out_w: Tensor<T>, // (hidden_size, )
-    pub lm_head: Tensor<T>,   // (vocab_size, dim)
+    pub lm_head: Weight<T>,   // (vocab_size, dim)
 }
 
-impl LLamaParams<f32> {
-    pub fn from_safetensors(safetensor: &SafeTensors, config: &LlamaConfigJson) -> Self {
-        // todo!("实现从safetensors文件的模型参数加载");
-        let get_tensor = |name: &str| {
-            let tensor_view = safetensor.tensor(name).expect("tensor not found");
-            let shape = tensor_view.shape().to_vec();
-            let data = tensor_view.data();
-            let ftype_ptr = data.as_ptr() as *const f32;
-            // *const [] and *mut [] are fat pointers, including the size of vec and the pointer to the data
-            // so we need to treat them as basic data ptr, like u8 and f32
-            // then we can create a Tensor from the slice
-            unsafe { Tensor::<f32>::new(std::slice::from_raw_parts(ftype_ptr, data.len() / 4).to_vec(), &shape) }
-        };
+// Fields holding weight matrices, i.e. the ones that can be quantized
+pub const MATRIX_FIELDS: [&str; 9] = [
+    "embedding_table",
+    "wq",
+    "wk",
+    "wv",
+    "wo",
+    "w_up",
+    "w_gate",
+    "w_down",
+    "lm_head",
+];
+
+// Fields quantized when none are named explicitly: every linear layer inside the decoder
+pub const DEFAULT_QUANT_FIELDS: [&str; 7] = ["wq", "wk", "wv", "wo", "w_up", "w_gate", "w_down"];
+
+impl<T: Float> LLamaParams<T> {
+    pub fn from_safetensors(checkpoint: &Checkpoint, config: &LlamaConfigJson) -> Self {
+        let get_tensor = |name: &str| checkpoint.tensor::<T>(name);
+        let get_weight = |name: &str| checkpoint.weight::<T>(name);
 
         let n_layers = config.num_hidden_layers;
 
+        // Tied checkpoints store one matrix under either name; it is loaded once and shared.
+        let (embedding_table, lm_head) = if config.tie_word_embeddings {
+            let name = ["lm_head.weight", "model.embed_tokens.weight"]
+                .into_iter()
+                .find(|name| checkpoint.contains(name))
+                .expect("tied checkpoint has neither lm_head.weight nor model.embed_tokens.weight");
+            let table = get_weight(name);
+            (table.clone(), table)
+        } else {
+            (
+                get_weight("model.embed_tokens.weight"),
+                get_weight("lm_head.weight"),
+            )
+        };
+
         LLamaParams {
-            embedding_table: get_tensor("lm_head.weight"),
+            embedding_table,
             rms_att_w: (0..n_layers)
                 .map(|i| get_tensor(&format!("model.layers.{i}.input_layernorm.weight")))
                 .collect(),
             wq: (0..n_layers)
-                .map(|i| get_tensor(&format!("model.layers.{i}.self_attn.q_proj.weight")))
+                .map(|i| get_weight(&format!("model.layers.{i}.self_attn.q_proj.weight")))
                 .collect(),
             wk: (0..n_layers)
-                .map(|i| get_tensor(&format!("model.layers.{i}.self_attn.k_proj.weight")))
+                .map(|i| get_weight(&format!("model.layers.{i}.self_attn.k_proj.weight")))
                 .collect(),
             wv: (0..n_layers)
-                .map(|i| get_tensor(&format!("model.layers.{i}.self_attn.v_proj.weight")))
+                .map(|i| get_weight(&format!("model.layers.{i}.self_attn.v_proj.weight")))
                 .collect(),
             wo: (0..n_layers)
-                .map(|i| get_tensor(&format!("model.layers.{i}.self_attn.o_proj.weight")))
+                .map(|i| get_weight(&format!("model.layers.{i}.self_attn.o_proj.weight")))
                 .collect(),
             rms_ffn_w: (0..n_layers)
                 .map(|i| get_tensor(&format!("model.layers.{i}.post_attention_layernorm.weight")))
                 .collect(),
             w_up: (0..n_layers)
-                .map(|i| get_tensor(&format!("model.layers.{i}.mlp.up_proj.weight")))
+                .map(|i| get_weight(&format!("model.layers.{i}.mlp.up_proj.weight")))
                 .collect(),
             w_gate: (0..n_layers)
-                .map(|i| get_tensor(&format!("model.layers.{i}.mlp.gate_proj.weight")))
+                .map(|i| get_weight(&format!("model.layers.{i}.mlp.gate_proj.weight")))
                 .collect(),
             w_down: (0..n_layers)
-                .map(|i| get_tensor(&format!("model.layers.{i}.mlp.down_proj.weight")))
+                .map(|i| get_weight(&format!("model.layers.{i}.mlp.down_proj.weight")))
                 .collect(),
             rms_out_w: get_tensor("model.norm.weight"),
-            lm_head: get_tensor("lm_head.weight"),
+            lm_head,
+        }
+    }
+
+    // Quantize the named matrix fields in place, e.g. `&["wq", "wk"]`; others stay dense.
+    #[allow(unused)]
+    pub fn quantize(&mut self, fields: &[&str], format: QuantFormat) {
+        let quantize_all = |ws: &mut Vec<Weight<T>>| {
+            ws.iter_mut().for_each(|w| *w = w.quantize(format));
+        };
+        for &field in fields {
+            match field {
+                "embedding_table" => self.embedding_table = self.embedding_table.quantize(format),
+                "wq" => quantize_all(&mut self.wq),
+                "wk" => quantize_all(&mut self.wk),
+                "wv" => quantize_all(&mut self.wv),
+                "wo" => quantize_all(&mut self.wo),
+                "w_up" => quantize_all(&mut self.w_up),
+                "w_gate" => quantize_all(&mut self.w_gate),
+                "w_down" => quantize_all(&mut self.w_down),
+                "lm_head" => self.lm_head = self.lm_head.quantize(format),
+                _ => panic!("{field} is not a weight matrix of LLamaParams"),
+            }
+        }
+    }
+}
+
+// A memory-mapped safetensors checkpoint, either a single `model.safetensors` or several shards
+// listed in `model.safetensors.index.json`. Tensors stored in the requested element type borrow
+// straight from the mapping, so loading costs no copy and the pages are shared between processes.
+pub struct Checkpoint {
+    shards: Vec<Shard>,
+    index: HashMap<String, usize>, // tensor name -> shard holding it
+}
+
+struct Shard {
+    mmap: Arc<Mmap>,
+    data_start: usize, // tensor offsets are relative to the end of the header
+    metadata: Metadata,
+}
+
+// model.safetensors.index.json, only the part we need
+#[derive(serde::Deserialize)]
+struct ShardIndex {
+    weight_map: HashMap<String, String>,
+}
+
+impl Checkpoint {
+    // Open the checkpoint in `model_dir`, following the shard index when there is one.
+    pub fn open_dir(model_dir: &Path) -> std::io::Result<Self> {
+        let index_path = model_dir.join("model.safetensors.index.json");
+        if !index_path.exists() {
+            return Self::open(&model_dir.join("model.safetensors"));
+        }
+        let index: ShardIndex = serde_json::from_reader(File::open(index_path)?)?;
+        let mut files = index.weight_map.values().cloned().collect::<Vec<_>>();
+        files.sort();
+        files.dedup();
+
+        let shards = files
+            .iter()
+            .map(|file| Shard::open(&model_dir.join(file)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let mut map = HashMap::new();
+        for (name, file) in index.weight_map {
+            let shard = files.binary_search(&file).unwrap();
+            if shards[shard].metadata.info(&name).is_none() {
+                let msg = format!("{name} is not in {file} as the index claims");
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+            }
+            map.insert(name, shard);
+        }
+        Ok(Checkpoint { shards, index: map })
+    }
+
+    // Open a single safetensors file.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let shard = Shard::open(path)?;
+        let index = shard
+            .metadata
+            .tensors()
+            .into_keys()
+            .map(|name| (name, 0))
+            .collect();
+        Ok(Checkpoint {
+            shards: vec![shard],
+            index,
+        })
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.index.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.index.contains_key(name)
+    }
+
+    pub fn info(&self, name: &str) -> &TensorInfo {
+        self.shard(name).metadata.info(name).unwrap()
+    }
+
+    pub fn bytes(&self, name: &str) -> &[u8] {
+        let shard = self.shard(name);
+        &shard.mmap[shard.byte_range(name)]
+    }
+
+    // A hash telling checkpoints apart, e.g. to refuse KV caches computed by another model: every
+    // tensor's name, type and shape, and up to 64 samples of 64 bytes from its data, so that it
+    // stays cheap for checkpoints of any size.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hash = FNV_OFFSET;
+        for name in self.names() {
+            let info = self.info(&name);
+            hash = fnv1a(
+                hash,
+                format!("{name} {:?} {:?}", info.dtype, info.shape).as_bytes(),
+            );
+            let bytes = self.bytes(&name);
+            for chunk in bytes.chunks((bytes.len() / 64).max(64)) {
+                hash = fnv1a(hash, &chunk[..chunk.len().min(64)]);
+            }
+        }
+        hash
+    }
+
+    // quantization format recorded by `quantize_safetensors`, if the tensor is quantized
+    pub fn format(&self, name: &str) -> Option<QuantFormat> {
+        let format = self.shard(name).metadata.metadata().as_ref()?.get(name)?;
+        Some(QuantFormat::parse(format).expect("unknown quantization format"))
+    }
+
+    pub fn tensor<T: Float>(&self, name: &str) -> Tensor<T> {
+        let info = self.info(name);
+        if is_dtype::<T>(info.dtype) {
+            let shard = self.shard(name);
+            let owner = shard.mmap.clone();
+            if let Some(t) =
+                unsafe { Tensor::from_bytes(owner, shard.byte_range(name), &info.shape) }
+            {
+                return t;
+            }
+        }
+        Tensor::new(convert(info.dtype, self.bytes(name)), &info.shape)
+    }
+
+    pub fn weight<T: Float>(&self, name: &str) -> Weight<T> {
+        match self.format(name) {
+            Some(format) => {
+                let shard = self.shard(name);
+                let shape = &self.info(name).shape;
+                let cols = shape[1] / format.block_bytes() * QK;
+                let blocks = unsafe {
+                    Tensor::<u8>::from_bytes(shard.mmap.clone(), shard.byte_range(name), shape)
+                }
+                .unwrap_or_else(|| Tensor::new(self.bytes(name).to_vec(), shape));
+                Weight::Quant(QTensor::from_blocks(format, blocks, cols))
+            }
+            None => Weight::Dense(self.tensor(name)),
         }
     }
+
+    fn shard(&self, name: &str) -> &Shard {
+        match self.index.get(name) {
+            Some(&i) => &self.shards[i],
+            None => panic!("tensor {name} not found"),
+        }
+    }
+}
+
+impl Shard {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        // the file is treated as immutable for as long as the mapping lives
+        let mmap = unsafe { Mmap::map(&file)? };
+        let (header_len, metadata) = SafeTensors::read_metadata(&mmap)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{e:?}")))?;
+        Ok(Shard {
+            mmap: Arc::new(mmap),
+            data_start: 8 + header_len,
+            metadata,
+        })
+    }
+
+    fn byte_range(&self, name: &str) -> Range<usize> {
+        let (start, end) = self.metadata.info(name).unwrap().data_offsets;
+        self.data_start + start..self.data_start + end
+    }
+}
+
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+// 64-bit FNV-1a, stable across builds unlike std's hashers
+pub fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes {
+        hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn is_dtype<T: 'static>(dtype: Dtype) -> bool {
+    let id = TypeId::of::<T>();
+    match dtype {
+        Dtype::F32 => id == TypeId::of::<f32>(),
+        Dtype::F16 => id == TypeId::of::<f16>(),
+        Dtype::BF16 => id == TypeId::of::<bf16>(),
+        _ => false,
+    }
+}
+
+// The LLamaParams fields a safetensors tensor is loaded into, if it is a weight matrix.
+// Tied checkpoints store one matrix, under either name, that feeds both embedding_table and
+// lm_head; untied ones keep one tensor per field.
+pub fn matrix_fields(name: &str, tie_word_embeddings: bool) -> &'static [&'static str] {
+    const SUFFIXES: [(&str, &[&str]); 7] = [
+        ("self_attn.q_proj.weight", &["wq"]),
+        ("self_attn.k_proj.weight", &["wk"]),
+        ("self_attn.v_proj.weight", &["wv"]),
+        ("self_attn.o_proj.weight", &["wo"]),
+        ("mlp.up_proj.weight", &["w_up"]),
+        ("mlp.gate_proj.weight", &["w_gate"]),
+        ("mlp.down_proj.weight", &["w_down"]),
+    ];
+    match name {
+        "lm_head.weight" | "model.embed_tokens.weight" if tie_word_embeddings => {
+            &["embedding_table", "lm_head"]
+        }
+        "lm_head.weight" => &["lm_head"],
+        "model.embed_tokens.weight" => &["embedding_table"],
+        _ if name.starts_with("model.layers.") => SUFFIXES
+            .iter()
+            .find(|(suffix, _)| name.ends_with(suffix))
+            .map_or(&[], |(_, fields)| fields),
+        _ => &[],
+    }
+}
+
+// Offline conversion: rewrite the checkpoint in `src_dir` (single file or sharded) into a single
+// `dst_dir/model.safetensors` with the tensors behind `fields` block-quantized. Quantized tensors
+// are stored as (rows, row_bytes) u8 arrays and their format is recorded in the file metadata.
+// Other files (config, tokenizer) are copied as is.
+pub fn quantize_safetensors(
+    src_dir: &Path,
+    dst_dir: &Path,
+    fields: &[&str],
+    format: QuantFormat,
+) -> std::io::Result<()> {
+    let checkpoint = Checkpoint::open_dir(src_dir)?;
+    let config: LlamaConfigJson =
+        serde_json::from_slice(&std::fs::read(src_dir.join("config.json"))?)?;
+
+    let mut blocks = HashMap::new();
+    let mut formats = HashMap::new();
+    for name in checkpoint.names() {
+        let info = checkpoint.info(&name);
+        let selected = matrix_fields(&name, config.tie_word_embeddings)
+            .iter()
+            .any(|f| fields.contains(f));
+        if let Some(existing) = checkpoint.format(&name) {
+            formats.insert(name, existing.name().to_string());
+        } else if selected && info.shape.len() == 2 && info.shape[1] % QK == 0 {
+            let q = QTensor::quantize(&checkpoint.tensor::<f32>(&name), format);
+            blocks.insert(name.clone(), q.blocks().clone());
+            formats.insert(name, format.name().to_string());
+        }
+    }
+
+    let tensors = checkpoint.names().into_iter().map(|name| {
+        let view = match blocks.get(&name) {
+            Some(b) => TensorView::new(Dtype::U8, b.shape().clone(), b.data()),
+            None => {
+                let info = checkpoint.info(&name);
+                TensorView::new(info.dtype, info.shape.clone(), checkpoint.bytes(&name))
+            }
+        };
+        (name, view.unwrap())
+    });
+
+    std::fs::create_dir_all(dst_dir)?;
+    safetensors::serialize_to_file(tensors, &Some(formats), &dst_dir.join("model.safetensors"))
+        .map_err(std::io::Error::other)?;
+    for entry in std::fs::read_dir(src_dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy();
+        if path.is_file() && !name.contains(".safetensors") {
+            std::fs::copy(&path, dst_dir.join(&*name))?;
+        }
+    }
+    Ok(())
+}
+
+// Decode little-endian raw data of a stored dtype into T, going through f32.
+// Widening to f32 is exact, so a file already in T's format round-trips losslessly.
+fn convert<T: Float>(dtype: Dtype, data: &[u8]) -> Vec<T> {
+    match dtype {
+        Dtype::F32 => data
+            .chunks_exact(4)
+            .map(|b| T::from_f32(f32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+            .collect(),
+        Dtype::F16 => data
+            .chunks_exact(2)
+            .map(|b| T::from_f32(f16::from_le_bytes([b[0], b[1]]).to_f32()))
+            .collect(),
+        Dtype::BF16 => data
+            .chunks_exact(2)
+            .map(|b| T::from_f32(bf16::from_le_bytes([b[0], b[1]]).to_f32()))
+            .collect(),
+        _ => panic!("unsupported weight dtype {dtype:?}"),
+    }
 }
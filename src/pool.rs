@@ -0,0 +1,163 @@
+use crate::operators::num_threads;
+use std::any::Any;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Worker threads shared by the matmul and attention kernels, started on first use with one
+// worker less than `num_threads()` (the calling thread works too), so later calls to
+// `set_num_threads` only lower how many of them a kernel asks for.
+struct Pool {
+    jobs: Mutex<Sender<Job>>,
+    workers: usize,
+}
+
+fn pool() -> &'static Pool {
+    static POOL: OnceLock<Pool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = num_threads().saturating_sub(1);
+        for i in 0..workers {
+            let queue = Arc::clone(&queue);
+            std::thread::Builder::new()
+                .name(format!("worker-{i}"))
+                .spawn(move || work(&queue))
+                .expect("failed to start a worker thread");
+        }
+        Pool {
+            jobs: Mutex::new(jobs),
+            workers,
+        }
+    })
+}
+
+fn work(queue: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = queue.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
+
+// One call to `map`: items are claimed by index, by the caller and by up to `threads - 1`
+// workers. The batch outlives the call, so a worker that is only scheduled after the caller has
+// returned finds every index claimed and never touches the caller's data behind `task`.
+struct Batch {
+    next: AtomicUsize,
+    len: usize,
+    unfinished: Mutex<usize>,
+    finished: Condvar,
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+    task: *const (dyn Fn(usize) + Sync),
+}
+
+// task is only dereferenced for an index claimed before `map` has seen every index finish
+unsafe impl Send for Batch {}
+unsafe impl Sync for Batch {}
+
+impl Batch {
+    fn help(&self) {
+        loop {
+            let i = self.next.fetch_add(1, Ordering::Relaxed);
+            if i >= self.len {
+                return;
+            }
+            let task = unsafe { &*self.task };
+            if let Err(payload) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| task(i)))
+            {
+                self.panic.lock().unwrap().get_or_insert(payload);
+            }
+            let mut unfinished = self.unfinished.lock().unwrap();
+            *unfinished -= 1;
+            if *unfinished == 0 {
+                self.finished.notify_all();
+            }
+        }
+    }
+}
+
+// f applied to every item on up to `threads` threads of the pool, results in item order.
+// Blocks until all items are done and re-raises the first panic of any of them.
+pub(crate) fn map<T: Send, R: Send>(
+    items: Vec<T>,
+    threads: usize,
+    f: impl Fn(T) -> R + Sync,
+) -> Vec<R> {
+    let len = items.len();
+    let items = items
+        .into_iter()
+        .map(|item| Mutex::new(Some(item)))
+        .collect::<Vec<_>>();
+    let results = (0..len).map(|_| Mutex::new(None)).collect::<Vec<_>>();
+    let task = |i: usize| {
+        let item = items[i].lock().unwrap().take().unwrap();
+        *results[i].lock().unwrap() = Some(f(item));
+    };
+    let task: &(dyn Fn(usize) + Sync) = &task;
+    // erase the lifetime of task; see Batch
+    let task: *const (dyn Fn(usize) + Sync + 'static) = unsafe { std::mem::transmute(task) };
+    let batch = Arc::new(Batch {
+        next: AtomicUsize::new(0),
+        len,
+        unfinished: Mutex::new(len),
+        finished: Condvar::new(),
+        panic: Mutex::new(None),
+        task,
+    });
+
+    let pool = pool();
+    let helpers = threads.min(len).saturating_sub(1).min(pool.workers);
+    if helpers > 0 {
+        let jobs = pool.jobs.lock().unwrap();
+        for _ in 0..helpers {
+            let batch = Arc::clone(&batch);
+            jobs.send(Box::new(move || batch.help())).unwrap();
+        }
+    }
+    batch.help();
+    let mut unfinished = batch.unfinished.lock().unwrap();
+    while *unfinished > 0 {
+        unfinished = batch.finished.wait(unfinished).unwrap();
+    }
+    drop(unfinished);
+    if let Some(payload) = batch.panic.lock().unwrap().take() {
+        std::panic::resume_unwind(payload);
+    }
+    results
+        .into_iter()
+        .map(|r| r.into_inner().unwrap().unwrap())
+        .collect()
+}
+
+#[test]
+fn test_map_keeps_order() {
+    let squares = map((0..100).collect(), 4, |i: usize| i * i);
+    assert_eq!(squares, (0..100).map(|i| i * i).collect::<Vec<_>>());
+
+    let mut data = vec![0; 10];
+    map(
+        data.chunks_mut(3).enumerate().collect(),
+        3,
+        |(t, chunk): (usize, &mut [usize])| chunk.fill(t),
+    );
+    assert_eq!(data, [0, 0, 0, 1, 1, 1, 2, 2, 2, 3]);
+}
+
+#[test]
+fn test_map_reraises_panics() {
+    let result = std::panic::catch_unwind(|| {
+        map(vec![1, 2, 3], 3, |i: i32| assert!(i != 2, "bad item {i}"))
+    });
+    let payload = result.unwrap_err();
+    assert_eq!(
+        payload.downcast_ref::<String>().map(String::as_str),
+        Some("bad item 2")
+    );
+    // the pool keeps working after a panic
+    assert_eq!(map(vec![1, 2, 3], 3, |i: i32| i + 1), [2, 3, 4]);
+}
@@ -0,0 +1,222 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use crate::kvcache::{BlockPool, KVCache};
+
+// A radix tree over token ids at block granularity: every node below the root is one full block
+// of the pool whose keys and values were computed for the tokens on the path to it. A new
+// sequence starts from the blocks of its longest cached prefix instead of recomputing them.
+// The tree holds a reference on each of its blocks; blocks no cache shares any more are evicted
+// least recently used first.
+pub struct PrefixCache {
+    pool: Arc<BlockPool<f32>>,
+    nodes: Vec<Option<Node>>, // index 0 is the root, None for evicted nodes
+    free: Vec<usize>,         // indices of evicted nodes, reused first
+    leaves: BTreeSet<(u64, usize)>, // (last_used, node) of every leaf below the root
+    tick: u64,
+}
+
+struct Node {
+    parent: usize,
+    tokens: Vec<u32>, // the block_size tokens of this block
+    block: usize,
+    children: HashMap<Vec<u32>, usize>,
+    last_used: u64,
+}
+
+#[allow(unused)]
+impl PrefixCache {
+    pub fn new(pool: Arc<BlockPool<f32>>) -> Self {
+        let root = Node {
+            parent: 0,
+            tokens: Vec::new(),
+            block: usize::MAX,
+            children: HashMap::new(),
+            last_used: 0,
+        };
+        Self {
+            pool,
+            nodes: vec![Some(root)],
+            free: Vec::new(),
+            leaves: BTreeSet::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn pool(&self) -> &Arc<BlockPool<f32>> {
+        &self.pool
+    }
+
+    // blocks held by the tree
+    pub fn num_blocks(&self) -> usize {
+        self.nodes.len() - self.free.len() - 1
+    }
+
+    // A paged cache holding the keys and values of the longest cached prefix of `tokens`, whole
+    // blocks only, at least one token short of `tokens` so the caller has logits to sample from.
+    // Its length is the number of tokens that need not be run again.
+    pub fn lookup(&mut self, tokens: &[u32]) -> KVCache<f32> {
+        self.tick += 1;
+        let (tick, block_size) = (self.tick, self.pool.block_size());
+        let mut node = 0;
+        let mut blocks = Vec::new();
+        for chunk in tokens[..tokens.len().saturating_sub(1)].chunks_exact(block_size) {
+            let Some(&child) = self.node(node).children.get(chunk) else {
+                break;
+            };
+            node = child;
+            self.touch(node, tick);
+            blocks.push(self.node(node).block);
+        }
+        KVCache::new_paged_from(&self.pool, &blocks)
+    }
+
+    // Remember the full blocks of `cache`, a cache of this pool that has run `tokens`, or at
+    // least their first cache.len().
+    pub fn insert(&mut self, tokens: &[u32], cache: &KVCache<f32>) {
+        self.tick += 1;
+        let table = cache.blocks().expect("only paged caches can be shared");
+        let len = cache.len().min(tokens.len());
+        let mut node = 0;
+        for (chunk, &block) in tokens[..len]
+            .chunks_exact(self.pool.block_size())
+            .zip(table)
+        {
+            node = match self.node(node).children.get(chunk) {
+                // already cached, possibly computed by another sequence
+                Some(&child) => child,
+                None => {
+                    self.pool.retain(&[block]);
+                    let child = self.add(Node {
+                        parent: node,
+                        tokens: chunk.to_vec(),
+                        block,
+                        children: HashMap::new(),
+                        last_used: self.tick,
+                    });
+                    let parent = self.node_mut(node);
+                    if parent.children.is_empty() {
+                        let key = (parent.last_used, node);
+                        self.leaves.remove(&key);
+                    }
+                    self.node_mut(node).children.insert(chunk.to_vec(), child);
+                    child
+                }
+            };
+            self.touch(node, self.tick);
+        }
+    }
+
+    // Evict least recently used blocks held by the tree alone until the pool has `free` free
+    // blocks or nothing more can go. Returns whether there are enough free blocks.
+    pub fn evict(&mut self, free: usize) -> bool {
+        // Only leaves, so that every remaining node still has its whole prefix. Leaves still
+        // shared by a cache are skipped; the scan resumes after the last one evicted.
+        let mut from = (0, 0);
+        while self.pool.num_free() < free {
+            let lru = self
+                .leaves
+                .range(from..)
+                .find(|&&(_, i)| self.pool.ref_count(self.node(i).block) == 1);
+            let Some(&(last_used, i)) = lru else {
+                return false;
+            };
+            from = (last_used, i);
+            self.leaves.remove(&from);
+            let node = self.nodes[i].take().unwrap();
+            self.free.push(i);
+            let parent = self.node_mut(node.parent);
+            parent.children.remove(&node.tokens);
+            if node.parent != 0 && parent.children.is_empty() {
+                let key = (parent.last_used, node.parent);
+                self.leaves.insert(key);
+                from = from.min(key);
+            }
+            self.pool.release(&[node.block]);
+        }
+        true
+    }
+
+    // a new leaf
+    fn add(&mut self, node: Node) -> usize {
+        let last_used = node.last_used;
+        let i = match self.free.pop() {
+            Some(i) => {
+                self.nodes[i] = Some(node);
+                i
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        self.leaves.insert((last_used, i));
+        i
+    }
+
+    fn touch(&mut self, i: usize, tick: u64) {
+        let n = self.nodes[i].as_mut().unwrap();
+        if i != 0 && n.children.is_empty() {
+            self.leaves.remove(&(n.last_used, i));
+            self.leaves.insert((tick, i));
+        }
+        n.last_used = tick;
+    }
+
+    fn node(&self, i: usize) -> &Node {
+        self.nodes[i].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, i: usize) -> &mut Node {
+        self.nodes[i].as_mut().unwrap()
+    }
+}
+
+// every cached block goes back to the pool with the tree
+impl Drop for PrefixCache {
+    fn drop(&mut self) {
+        let blocks = self
+            .nodes
+            .iter()
+            .skip(1)
+            .flatten()
+            .map(|n| n.block)
+            .collect::<Vec<_>>();
+        self.pool.release(&blocks);
+    }
+}
+
+#[test]
+fn test_prefix_cache_eviction() {
+    let pool = Arc::new(BlockPool::<f32>::new(1, 8, 2, 4));
+    let mut prefixes = PrefixCache::new(pool.clone());
+    let run = |tokens: &[u32], prefixes: &mut PrefixCache| {
+        let mut cache = prefixes.lookup(tokens);
+        let reused = cache.len();
+        cache.increment(tokens.len() - reused).unwrap();
+        prefixes.insert(tokens, &cache);
+        (cache, reused)
+    };
+
+    let (a, reused) = run(&[1, 2, 3, 4, 5], &mut prefixes);
+    assert_eq!((reused, prefixes.num_blocks()), (0, 2));
+    // a shared prefix of 3 tokens, of which one block is reused
+    let (b, reused) = run(&[1, 2, 3, 9, 9, 9], &mut prefixes);
+    assert_eq!((reused, prefixes.num_blocks()), (2, 4));
+    assert_eq!(b.blocks().unwrap()[0], a.blocks().unwrap()[0]);
+    assert_eq!(pool.ref_count(a.blocks().unwrap()[0]), 3);
+    // the last token is always run again
+    assert_eq!(prefixes.lookup(&[1, 2, 3, 4]).len(), 2);
+    assert_eq!(pool.num_free(), 8 - 3 - 2);
+
+    // nothing to evict while the sequences are alive
+    assert!(!prefixes.evict(4));
+    drop(a);
+    // [3, 4] of the first sequence is the least recently used leaf
+    assert!(prefixes.evict(5));
+    assert_eq!(prefixes.lookup(&[1, 2, 3, 4, 5]).len(), 2);
+    assert_eq!(prefixes.lookup(&[1, 2, 3, 9, 9, 9]).len(), 4);
+    drop(b);
+    assert!(prefixes.evict(8));
+    assert_eq!(prefixes.num_blocks(), 0);
+}
@@ -0,0 +1,274 @@
+use crate::operators::{num_threads, par_tiles};
+use crate::tensor::{Float, Tensor};
+use half::f16;
+
+// number of values sharing one scale
+pub const QK: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QuantFormat {
+    Q8_0, // f16 scale + 32 x i8
+    Q4_0, // f16 scale + 32 x 4-bit, packed two per byte
+}
+
+impl QuantFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "q8_0" => Some(QuantFormat::Q8_0),
+            "q4_0" => Some(QuantFormat::Q4_0),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            QuantFormat::Q8_0 => "q8_0",
+            QuantFormat::Q4_0 => "q4_0",
+        }
+    }
+
+    pub fn block_bytes(self) -> usize {
+        match self {
+            QuantFormat::Q8_0 => 2 + QK,
+            QuantFormat::Q4_0 => 2 + QK / 2,
+        }
+    }
+}
+
+// A (rows, cols) matrix quantized row by row into blocks of QK values.
+// The raw blocks live in a (rows, row_bytes) byte tensor.
+#[derive(Clone)]
+pub struct QTensor {
+    format: QuantFormat,
+    blocks: Tensor<u8>,
+    shape: Vec<usize>,
+}
+
+impl QTensor {
+    pub fn from_blocks(format: QuantFormat, blocks: Tensor<u8>, cols: usize) -> Self {
+        let rows = blocks.shape()[0];
+        assert_eq!(cols % QK, 0, "row length must be a multiple of {QK}");
+        assert_eq!(blocks.shape()[1], cols / QK * format.block_bytes());
+        QTensor {
+            format,
+            blocks,
+            shape: vec![rows, cols],
+        }
+    }
+
+    pub fn quantize<T: Float>(t: &Tensor<T>, format: QuantFormat) -> Self {
+        assert_eq!(t.shape().len(), 2, "only matrices can be quantized");
+        let (rows, cols) = (t.shape()[0], t.shape()[1]);
+        assert_eq!(cols % QK, 0, "row length must be a multiple of {QK}");
+        let row_bytes = cols / QK * format.block_bytes();
+        let mut bytes = Vec::with_capacity(rows * row_bytes);
+        let mut block = [0f32; QK];
+        for values in t.contiguous().data().chunks_exact(QK) {
+            block
+                .iter_mut()
+                .zip(values)
+                .for_each(|(b, v)| *b = v.to_f32());
+            match format {
+                QuantFormat::Q8_0 => quantize_q8_0(&block, &mut bytes),
+                QuantFormat::Q4_0 => quantize_q4_0(&block, &mut bytes),
+            }
+        }
+        Self::from_blocks(format, Tensor::new(bytes, &vec![rows, row_bytes]), cols)
+    }
+
+    #[allow(unused)]
+    pub fn dequantize(&self) -> Tensor<f32> {
+        let mut out = Tensor::<f32>::default(&self.shape);
+        let data = unsafe { out.data_mut() };
+        for (r, row) in data.chunks_exact_mut(self.shape[1]).enumerate() {
+            self.dequantize_row(r, row);
+        }
+        out
+    }
+
+    pub fn dequantize_row(&self, r: usize, out: &mut [f32]) {
+        let bb = self.format.block_bytes();
+        for (block, dst) in self.row(r).chunks_exact(bb).zip(out.chunks_exact_mut(QK)) {
+            let d = scale(block);
+            match self.format {
+                QuantFormat::Q8_0 => {
+                    for (o, &q) in dst.iter_mut().zip(&block[2..]) {
+                        *o = q as i8 as f32 * d;
+                    }
+                }
+                QuantFormat::Q4_0 => {
+                    for (j, &q) in block[2..].iter().enumerate() {
+                        dst[j] = ((q & 0x0f) as i32 - 8) as f32 * d;
+                        dst[j + QK / 2] = ((q >> 4) as i32 - 8) as f32 * d;
+                    }
+                }
+            }
+        }
+    }
+
+    // dot product of row `r` with a dense vector, block by block
+    pub fn dot_row(&self, r: usize, x: &[f32]) -> f32 {
+        let bb = self.format.block_bytes();
+        let mut sum = 0.0;
+        for (block, xs) in self.row(r).chunks_exact(bb).zip(x.chunks_exact(QK)) {
+            let mut acc = 0.0;
+            match self.format {
+                QuantFormat::Q8_0 => {
+                    for (&q, &v) in block[2..].iter().zip(xs) {
+                        acc += q as i8 as f32 * v;
+                    }
+                }
+                QuantFormat::Q4_0 => {
+                    for (j, &q) in block[2..].iter().enumerate() {
+                        acc += ((q & 0x0f) as i32 - 8) as f32 * xs[j];
+                        acc += ((q >> 4) as i32 - 8) as f32 * xs[j + QK / 2];
+                    }
+                }
+            }
+            sum += acc * scale(block);
+        }
+        sum
+    }
+
+    #[allow(unused)]
+    pub fn format(&self) -> QuantFormat {
+        self.format
+    }
+
+    pub fn shape(&self) -> &Vec<usize> {
+        &self.shape
+    }
+
+    pub fn blocks(&self) -> &Tensor<u8> {
+        &self.blocks
+    }
+
+    fn row(&self, r: usize) -> &[u8] {
+        let row_bytes = self.blocks.shape()[1];
+        &self.blocks.data()[r * row_bytes..][..row_bytes]
+    }
+}
+
+#[inline]
+fn scale(block: &[u8]) -> f32 {
+    f16::from_le_bytes([block[0], block[1]]).to_f32()
+}
+
+// d = max|x| / 127, q = round(x / d)
+fn quantize_q8_0(x: &[f32; QK], out: &mut Vec<u8>) {
+    let amax = x.iter().fold(0f32, |m, v| m.max(v.abs()));
+    let d = amax / 127.0;
+    let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+    out.extend_from_slice(&f16::from_f32(d).to_le_bytes());
+    out.extend(x.iter().map(|v| (v * id).round() as i8 as u8));
+}
+
+// The same for a row of any length with an f32 scale, e.g. one head of a cached key: returns d
+pub fn quantize_i8(x: &[f32], q: &mut [i8]) -> f32 {
+    let amax = x.iter().fold(0f32, |m, v| m.max(v.abs()));
+    let d = amax / 127.0;
+    let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+    q.iter_mut()
+        .zip(x)
+        .for_each(|(q, v)| *q = (v * id).round() as i8);
+    d
+}
+
+// d = max / -8 (signed value of largest magnitude), q = x / d + 8 in [0, 15]
+fn quantize_q4_0(x: &[f32; QK], out: &mut Vec<u8>) {
+    let max = x
+        .iter()
+        .fold(0f32, |m, &v| if v.abs() > m.abs() { v } else { m });
+    let d = max / -8.0;
+    let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+    out.extend_from_slice(&f16::from_f32(d).to_le_bytes());
+    let q = |v: f32| ((v * id + 8.5) as u8).min(15);
+    out.extend((0..QK / 2).map(|j| q(x[j]) | (q(x[j + QK / 2]) << 4)));
+}
+
+// C = beta * C + alpha * A @ B^T with B quantized; B is consumed block by block, never expanded
+pub fn matmul_transb_q(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &QTensor, alpha: f32) {
+    let (m, k) = (a.shape()[0], a.shape()[1]);
+    let (n, k_b) = (b.shape()[0], b.shape()[1]);
+    assert_eq!(k, k_b, "B must have the same number of columns as A");
+    assert_eq!(c.shape(), &[m, n], "C must have shape [m, n]");
+
+    let a = a.contiguous();
+    let a_data = a.data();
+    par_tiles(c, k, num_threads(), |c_tile, ldc, rows, cols| {
+        for (ti, i) in rows.enumerate() {
+            let a_row = &a_data[i * k..][..k];
+            for (tj, j) in cols.clone().enumerate() {
+                let c_ij = &mut c_tile[ti * ldc + tj];
+                *c_ij = beta * *c_ij + alpha * b.dot_row(j, a_row);
+            }
+        }
+    });
+}
+
+// A weight matrix that is either stored densely in T or block-quantized
+#[derive(Clone)]
+pub enum Weight<T> {
+    Dense(Tensor<T>),
+    Quant(QTensor),
+}
+
+impl<T: Float> Weight<T> {
+    // raw values of a dense weight
+    #[allow(unused)]
+    pub fn data(&self) -> &[T] {
+        match self {
+            Weight::Dense(t) => t.data(),
+            Weight::Quant(q) => panic!("{} weight has no dense data", q.format().name()),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn quantize(&self, format: QuantFormat) -> Self {
+        match self {
+            Weight::Dense(t) => Weight::Quant(QTensor::quantize(t, format)),
+            Weight::Quant(q) if q.format() == format => self.clone(),
+            Weight::Quant(q) => Weight::Quant(QTensor::quantize(&q.dequantize(), format)),
+        }
+    }
+}
+
+#[test]
+fn test_quantize_roundtrip() {
+    let values = (0..64).map(|i| (i as f32 - 31.5) / 8.0).collect::<Vec<_>>();
+    let t = Tensor::<f32>::new(values, &vec![2, 32]);
+    // half a step (amax / 127 / 2) for q8_0; q4_0 clamps -max to 7 steps, so up to a full step
+    // (amax / 8)
+    for (format, tol) in [(QuantFormat::Q8_0, 0.02), (QuantFormat::Q4_0, 0.5)] {
+        let q = QTensor::quantize(&t, format);
+        assert_eq!(q.blocks().shape(), &vec![2, format.block_bytes()]);
+        let dq = q.dequantize();
+        for (x, y) in t.data().iter().zip(dq.data()) {
+            assert!((x - y).abs() <= tol, "{format:?}: {x} vs {y}");
+        }
+    }
+}
+
+#[test]
+fn test_matmul_transb_q() {
+    use crate::operators::matmul_transb;
+    let a = Tensor::<f32>::new(
+        (0..3 * 64).map(|i| (i % 7) as f32 - 3.0).collect(),
+        &vec![3, 64],
+    );
+    let b = Tensor::<f32>::new(
+        (0..5 * 64)
+            .map(|i| ((i * 13) % 11) as f32 / 10.0 - 0.5)
+            .collect(),
+        &vec![5, 64],
+    );
+    for format in [QuantFormat::Q8_0, QuantFormat::Q4_0] {
+        let q = QTensor::quantize(&b, format);
+        // the quantized kernel must agree with a dense matmul on the dequantized weights
+        let mut expected = Tensor::<f32>::new(vec![1.; 15], &vec![3, 5]);
+        matmul_transb(&mut expected, 0.5, &a, &q.dequantize(), 2.0);
+        let mut c = Tensor::<f32>::new(vec![1.; 15], &vec![3, 5]);
+        matmul_transb_q(&mut c, 0.5, &a, &q, 2.0);
+        assert!(c.close_to(&expected, 1e-4));
+    }
+}
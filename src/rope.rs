@@ -0,0 +1,576 @@
+use crate::config::{LlamaConfigJson, RopeScalingJson};
+use crate::tensor::Tensor;
+use std::f64::consts::PI;
+use std::ops::Range;
+use std::sync::{Mutex, RwLock};
+
+// How the rotary frequencies are stretched to reach past the context length a model was
+// trained on, following the `rope_scaling` types of transformers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RopeScaling {
+    None,
+    // positions divided by `factor`
+    Linear {
+        factor: f32,
+    },
+    // NTK-aware: the base grows with the sequence once it is longer than `original_max`
+    Dynamic {
+        factor: f32,
+        original_max: usize,
+    },
+    // high frequencies extrapolated, low ones interpolated, ramp in between
+    Yarn {
+        factor: f32,
+        original_max: usize,
+        beta_fast: f32,
+        beta_slow: f32,
+        attention_factor: f32, // cos and sin are scaled by this
+    },
+    // wavelengths above original_max / low_freq_factor interpolated, below
+    // original_max / high_freq_factor kept, smooth in between
+    Llama3 {
+        factor: f32,
+        original_max: usize,
+        low_freq_factor: f32,
+        high_freq_factor: f32,
+    },
+}
+
+impl RopeScaling {
+    // the scaling described by a config's rope_scaling, failing on a type this crate does not know
+    pub fn from_config(
+        json: Option<&RopeScalingJson>,
+        max_position_embeddings: usize,
+    ) -> std::io::Result<Self> {
+        let Some(json) = json else {
+            return Ok(RopeScaling::None);
+        };
+        let kind = json
+            .rope_type
+            .as_deref()
+            .or(json.legacy_type.as_deref())
+            .unwrap_or("default");
+        let factor = json.factor.unwrap_or(1.);
+        let original_max = json
+            .original_max_position_embeddings
+            .unwrap_or(max_position_embeddings);
+        Ok(match kind {
+            "default" => RopeScaling::None,
+            "linear" => RopeScaling::Linear { factor },
+            "dynamic" => RopeScaling::Dynamic {
+                factor,
+                original_max,
+            },
+            "yarn" => {
+                let attention_factor =
+                    json.attention_factor
+                        .unwrap_or(match (json.mscale, json.mscale_all_dim) {
+                            (Some(m), Some(m_all)) => {
+                                yarn_mscale(factor, m) / yarn_mscale(factor, m_all)
+                            }
+                            _ => yarn_mscale(factor, 1.),
+                        });
+                RopeScaling::Yarn {
+                    factor,
+                    original_max,
+                    beta_fast: json.beta_fast.unwrap_or(32.),
+                    beta_slow: json.beta_slow.unwrap_or(1.),
+                    attention_factor,
+                }
+            }
+            "llama3" => RopeScaling::Llama3 {
+                factor,
+                original_max,
+                low_freq_factor: json.low_freq_factor.unwrap_or(1.),
+                high_freq_factor: json.high_freq_factor.unwrap_or(4.),
+            },
+            other => {
+                let msg = format!("unsupported rope_scaling type {other:?}");
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+            }
+        })
+    }
+}
+
+// Which dimensions of a head are rotated together
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RopeLayout {
+    Halves,      // (i, i + d / 2), GPT-NeoX and Llama
+    Interleaved, // (2i, 2i + 1), GPT-J
+}
+
+impl RopeLayout {
+    // the layout checkpoints of the given transformers model_type were trained with
+    pub fn from_model_type(model_type: Option<&str>) -> Self {
+        match model_type {
+            Some("gptj" | "codegen") => RopeLayout::Interleaved,
+            _ => RopeLayout::Halves,
+        }
+    }
+}
+
+fn yarn_mscale(factor: f32, mscale: f32) -> f32 {
+    if factor <= 1. {
+        1.
+    } else {
+        0.1 * mscale * factor.ln() + 1.
+    }
+}
+
+// Dynamic scaling keeps the tables of the DYNAMIC_TABLES most recently used position ranges,
+// e.g. for the sequences of a batch that are as long as each other
+const DYNAMIC_TABLES: usize = 4;
+
+// cos and sin, each (positions, dim / 2)
+type Tables = (Tensor<f32>, Tensor<f32>);
+
+// Rotary position embedding of one model: the frequencies of each rotated pair and cos/sin
+// tables for every position seen so far, shared by all layers and heads.
+pub struct RotaryEmbedding {
+    dim: usize, // rotated dimensions of a head, the rest pass through unchanged
+    layout: RopeLayout,
+    theta: f32,
+    scaling: RopeScaling,
+    inv_freq: Vec<f64>, // (dim / 2) for sequences within the trained context
+    tables: RwLock<(Tensor<f32>, Tensor<f32>)>, // cos, sin: (positions, dim / 2), grown on demand
+    dynamic: Mutex<Vec<(Range<usize>, Tables)>>, // by positions, most recently used last
+}
+
+impl RotaryEmbedding {
+    pub fn new(dim: usize, theta: f32, scaling: RopeScaling, layout: RopeLayout) -> Self {
+        assert!(dim.is_multiple_of(2), "rotary dimension must be even");
+        let mut rope = RotaryEmbedding {
+            dim,
+            layout,
+            theta,
+            scaling,
+            inv_freq: Vec::new(),
+            tables: RwLock::new((
+                Tensor::default(&vec![0, dim / 2]),
+                Tensor::default(&vec![0, dim / 2]),
+            )),
+            dynamic: Mutex::new(Vec::new()),
+        };
+        rope.inv_freq = rope.frequencies(0);
+        rope
+    }
+
+    pub fn from_config(config: &LlamaConfigJson) -> std::io::Result<Self> {
+        let scaling =
+            RopeScaling::from_config(config.rope_scaling.as_ref(), config.max_position_embeddings)?;
+        let layout = RopeLayout::from_model_type(config.model_type.as_deref());
+        let head_dim = config.hidden_size / config.num_attention_heads;
+        let dim = match (config.rotary_dim, config.partial_rotary_factor) {
+            (Some(dim), _) => dim,
+            (None, Some(factor)) => (head_dim as f32 * factor) as usize,
+            (None, None) => head_dim,
+        };
+        // dimensions are rotated in pairs, a last odd one is left as it is
+        let dim = dim - dim % 2;
+        assert!(
+            dim <= head_dim,
+            "cannot rotate {dim} of {head_dim} head dimensions"
+        );
+        Ok(Self::new(dim, config.rope_theta, scaling, layout))
+    }
+
+    pub fn layout(&self) -> RopeLayout {
+        self.layout
+    }
+
+    #[allow(unused)]
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn scaling(&self) -> RopeScaling {
+        self.scaling
+    }
+
+    // cos and sin, each (positions.len(), dim / 2), for the given positions of a sequence
+    // that is positions.end long
+    pub fn tables(&self, positions: Range<usize>) -> (Tensor<f32>, Tensor<f32>) {
+        let shape = vec![positions.len(), self.dim / 2];
+        if let RopeScaling::Dynamic { original_max, .. } = self.scaling {
+            // the base depends on the exact sequence length
+            if positions.end > original_max {
+                let mut dynamic = self.dynamic.lock().unwrap();
+                let entry = match dynamic.iter().position(|(range, _)| *range == positions) {
+                    Some(i) => dynamic.remove(i),
+                    None => {
+                        if dynamic.len() == DYNAMIC_TABLES {
+                            dynamic.remove(0);
+                        }
+                        let tables =
+                            self.build(&self.frequencies(positions.end), positions.clone());
+                        (positions, tables)
+                    }
+                };
+                let tables = entry.1.clone();
+                dynamic.push(entry);
+                return tables;
+            }
+        }
+        {
+            let tables = self.tables.read().unwrap();
+            if tables.0.shape()[0] >= positions.end {
+                let start = positions.start * self.dim / 2;
+                return (tables.0.slice(start, &shape), tables.1.slice(start, &shape));
+            }
+        }
+        let mut tables = self.tables.write().unwrap();
+        if tables.0.shape()[0] < positions.end {
+            let len = positions.end.max(2 * tables.0.shape()[0]).max(64);
+            *tables = self.build(&self.inv_freq, 0..len);
+        }
+        let start = positions.start * self.dim / 2;
+        (tables.0.slice(start, &shape), tables.1.slice(start, &shape))
+    }
+
+    // cos and sin, each (rows, dim / 2), of a rotation `delta` positions back: applied to keys
+    // already rotated for position p they give the keys of position p - delta. The frequencies
+    // are those of the trained context, and there is no attention factor to apply twice: keys
+    // past the original context of Dynamic scaling were rotated with others and cannot be moved.
+    pub fn rotate_back(&self, delta: usize, rows: usize) -> (Tensor<f32>, Tensor<f32>) {
+        let (cos, sin): (Vec<f32>, Vec<f32>) = self
+            .inv_freq
+            .iter()
+            .map(|&f| {
+                let (s, c) = (delta as f64 * f).sin_cos();
+                (c as f32, -s as f32)
+            })
+            .unzip();
+        let shape = vec![rows, self.dim / 2];
+        (
+            Tensor::new(cos.repeat(rows), &shape),
+            Tensor::new(sin.repeat(rows), &shape),
+        )
+    }
+
+    fn build(&self, inv_freq: &[f64], positions: Range<usize>) -> (Tensor<f32>, Tensor<f32>) {
+        let scale = match self.scaling {
+            RopeScaling::Yarn {
+                attention_factor, ..
+            } => attention_factor as f64,
+            _ => 1.,
+        };
+        let n = positions.len();
+        let mut cos = Vec::with_capacity(n * inv_freq.len());
+        let mut sin = Vec::with_capacity(n * inv_freq.len());
+        for pos in positions {
+            for &f in inv_freq {
+                let (s, c) = (pos as f64 * f).sin_cos();
+                cos.push((c * scale) as f32);
+                sin.push((s * scale) as f32);
+            }
+        }
+        let shape = vec![n, inv_freq.len()];
+        (Tensor::new(cos, &shape), Tensor::new(sin, &shape))
+    }
+
+    // angular frequency of each rotated pair for a sequence of seq_len positions
+    fn frequencies(&self, seq_len: usize) -> Vec<f64> {
+        let dim = self.dim as f64;
+        let base_freq =
+            |base: f64| (0..self.dim / 2).map(move |i| base.powf(-((2 * i) as f64) / dim));
+        let theta = self.theta as f64;
+        match self.scaling {
+            RopeScaling::None => base_freq(theta).collect(),
+            RopeScaling::Linear { factor } => base_freq(theta).map(|f| f / factor as f64).collect(),
+            RopeScaling::Dynamic {
+                factor,
+                original_max,
+            } => {
+                let factor = factor as f64;
+                let base = if seq_len > original_max {
+                    let grow = factor * seq_len as f64 / original_max as f64 - (factor - 1.);
+                    theta * grow.powf(dim / (dim - 2.))
+                } else {
+                    theta
+                };
+                base_freq(base).collect()
+            }
+            RopeScaling::Yarn {
+                factor,
+                original_max,
+                beta_fast,
+                beta_slow,
+                ..
+            } => {
+                // dimension whose wavelength fits `rotations` times into the original context
+                let correction_dim = |rotations: f32| {
+                    dim * (original_max as f64 / (rotations as f64 * 2. * PI)).ln()
+                        / (2. * theta.ln())
+                };
+                let low = correction_dim(beta_fast).floor().max(0.);
+                let mut high = correction_dim(beta_slow).ceil().min(dim - 1.);
+                if low == high {
+                    high += 0.001;
+                }
+                base_freq(theta)
+                    .enumerate()
+                    .map(|(i, f)| {
+                        let ramp = ((i as f64 - low) / (high - low)).clamp(0., 1.);
+                        let extrapolate = 1. - ramp;
+                        f / factor as f64 * (1. - extrapolate) + f * extrapolate
+                    })
+                    .collect()
+            }
+            RopeScaling::Llama3 {
+                factor,
+                original_max,
+                low_freq_factor,
+                high_freq_factor,
+            } => {
+                let (factor, low, high) = (
+                    factor as f64,
+                    low_freq_factor as f64,
+                    high_freq_factor as f64,
+                );
+                let low_freq_wavelen = original_max as f64 / low;
+                let high_freq_wavelen = original_max as f64 / high;
+                base_freq(theta)
+                    .map(|f| {
+                        let wavelen = 2. * PI / f;
+                        if wavelen < high_freq_wavelen {
+                            f
+                        } else if wavelen > low_freq_wavelen {
+                            f / factor
+                        } else {
+                            let smooth = (original_max as f64 / wavelen - low) / (high - low);
+                            (1. - smooth) * f / factor + smooth * f
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[test]
+fn test_rope_tables() {
+    use crate::operators::rope;
+    let (dim, theta) = (8, 1e4f32);
+    let rope_emb = RotaryEmbedding::new(dim, theta, RopeScaling::None, RopeLayout::Halves);
+    let (cos, sin) = rope_emb.tables(3..5);
+    assert_eq!(cos.shape(), &vec![2, dim / 2]);
+    for (t, pos) in (3..5).enumerate() {
+        for i in 0..dim / 2 {
+            let angle = pos as f32 / theta.powf((2 * i) as f32 / dim as f32);
+            assert!((cos.data()[t * dim / 2 + i] - angle.cos()).abs() < 1e-6);
+            assert!((sin.data()[t * dim / 2 + i] - angle.sin()).abs() < 1e-6);
+        }
+    }
+    // growing the tables past their first allocation keeps earlier rows
+    let (far_cos, _) = rope_emb.tables(200..201);
+    let (cos_again, _) = rope_emb.tables(3..5);
+    assert_eq!(cos_again.data(), cos.data());
+    let angle = 200. / theta.powf(2. / dim as f32);
+    assert!((far_cos.data()[1] - angle.cos()).abs() < 1e-4);
+
+    // rotating (x, 0) by the table angle of position 4 on the first pair
+    let mut y = Tensor::<f32>::default(&vec![1, 1, dim]);
+    unsafe { y.data_mut()[0] = 1. };
+    let (cos, sin) = rope_emb.tables(4..5);
+    rope(&mut y, &cos, &sin, RopeLayout::Halves);
+    assert!((y.data()[0] - 4f32.cos()).abs() < 1e-6);
+    assert!((y.data()[dim / 2] - 4f32.sin()).abs() < 1e-6);
+}
+
+#[test]
+fn test_rope_scaling() {
+    let (dim, theta) = (16, 5e5);
+    let plain = RotaryEmbedding::new(dim, theta, RopeScaling::None, RopeLayout::Halves);
+    let close = |a: &[f64], b: &[f64]| {
+        a.iter()
+            .zip(b)
+            .all(|(x, y)| (x - y).abs() <= 1e-12 + 1e-9 * y.abs())
+    };
+
+    // linear: position 8 looks like position 2 did
+    let linear = RotaryEmbedding::new(
+        dim,
+        theta,
+        RopeScaling::Linear { factor: 4. },
+        RopeLayout::Halves,
+    );
+    assert_eq!(linear.tables(8..9).0.data(), plain.tables(2..3).0.data());
+
+    // dynamic: unchanged inside the original context, a larger base past it
+    let dynamic = RotaryEmbedding::new(
+        dim,
+        theta,
+        RopeScaling::Dynamic {
+            factor: 2.,
+            original_max: 64,
+        },
+        RopeLayout::Halves,
+    );
+    assert_eq!(dynamic.tables(0..64).1.data(), plain.tables(0..64).1.data());
+    let (long, short) = (dynamic.frequencies(128), plain.frequencies(128));
+    assert_eq!(long[0], short[0]);
+    assert!(long[1..].iter().zip(&short[1..]).all(|(l, s)| l < s));
+    let expected_base = theta as f64 * 3f64.powf(dim as f64 / (dim as f64 - 2.));
+    assert!((long[1] - expected_base.powf(-2. / dim as f64)).abs() < 1e-12);
+    // the base of the transformers formula for the exact length, whatever the range asked for
+    for end in [65, 100, 128] {
+        let grow = 2. * end as f64 / 64. - 1.;
+        let base = theta as f64 * grow.powf(dim as f64 / (dim as f64 - 2.));
+        let (cos, sin) = dynamic.tables(end - 1..end);
+        for i in 0..dim / 2 {
+            let angle = (end - 1) as f64 * base.powf(-((2 * i) as f64) / dim as f64);
+            assert!((cos.data()[i] as f64 - angle.cos()).abs() < 1e-6);
+            assert!((sin.data()[i] as f64 - angle.sin()).abs() < 1e-6);
+        }
+    }
+    // asked again, a range is served from the tables already built
+    dynamic.tables(64..65);
+    assert_eq!(dynamic.dynamic.lock().unwrap().len(), 3);
+
+    // llama3: short wavelengths kept, long ones divided by the factor
+    let llama3 = RotaryEmbedding::new(
+        dim,
+        theta,
+        RopeScaling::Llama3 {
+            factor: 8.,
+            original_max: 8192,
+            low_freq_factor: 1.,
+            high_freq_factor: 4.,
+        },
+        RopeLayout::Halves,
+    );
+    let f = llama3.frequencies(0);
+    assert!(close(&f[..2], &plain.inv_freq[..2]));
+    assert!(close(
+        &f[dim / 2 - 1..],
+        &[plain.inv_freq[dim / 2 - 1] / 8.]
+    ));
+
+    // yarn: same split by rotation count, plus the attention factor on cos and sin
+    let json: RopeScalingJson = serde_json::from_str(
+        r#"{"rope_type": "yarn", "factor": 4.0, "original_max_position_embeddings": 4096}"#,
+    )
+    .unwrap();
+    let scaling = RopeScaling::from_config(Some(&json), 16384).unwrap();
+    let RopeScaling::Yarn {
+        attention_factor, ..
+    } = scaling
+    else {
+        panic!("expected yarn, got {scaling:?}");
+    };
+    assert!((attention_factor - (0.1 * 4f32.ln() + 1.)).abs() < 1e-6);
+    let yarn = RotaryEmbedding::new(dim, theta, scaling, RopeLayout::Halves);
+    let f = yarn.frequencies(0);
+    assert!(close(&f[..1], &plain.inv_freq[..1]));
+    assert!(close(
+        &f[dim / 2 - 1..],
+        &[plain.inv_freq[dim / 2 - 1] / 4.]
+    ));
+    assert!((yarn.tables(0..1).0.data()[0] - attention_factor).abs() < 1e-6);
+}
+
+#[test]
+fn test_rope_scaling_config() {
+    let try_parse =
+        |s: &str| RopeScaling::from_config(Some(&serde_json::from_str(s).unwrap()), 2048);
+    let parse = |s: &str| try_parse(s).unwrap();
+    assert_eq!(
+        RopeScaling::from_config(None, 2048).unwrap(),
+        RopeScaling::None
+    );
+    assert_eq!(
+        parse(r#"{"type": "linear", "factor": 2.0}"#),
+        RopeScaling::Linear { factor: 2. }
+    );
+    assert_eq!(
+        parse(r#"{"type": "dynamic", "rope_type": "dynamic", "factor": 2.0}"#),
+        RopeScaling::Dynamic {
+            factor: 2.,
+            original_max: 2048
+        }
+    );
+    assert_eq!(
+        parse(
+            r#"{"rope_type": "llama3", "factor": 8.0, "low_freq_factor": 1.0, "high_freq_factor": 4.0,
+                "original_max_position_embeddings": 8192}"#
+        ),
+        RopeScaling::Llama3 {
+            factor: 8.,
+            original_max: 8192,
+            low_freq_factor: 1.,
+            high_freq_factor: 4.
+        }
+    );
+    let error = try_parse(r#"{"rope_type": "longrope", "factor": 2.0}"#).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        r#"unsupported rope_scaling type "longrope""#
+    );
+}
+
+#[test]
+fn test_rope_layouts() {
+    use crate::operators::rope;
+    let (n_heads, head_dim, rot_dim) = (2, 8, 4);
+    let rope_emb = RotaryEmbedding::new(rot_dim, 1e4, RopeScaling::None, RopeLayout::Interleaved);
+    let (cos, sin) = rope_emb.tables(2..5);
+    let x = (0..3 * n_heads * head_dim)
+        .map(|i| (i as f32 * 0.7).sin())
+        .collect::<Vec<_>>();
+
+    // a head in interleaved order, and the same values moved to halves order
+    let to_halves = |head: &[f32]| {
+        let mut out = head.to_vec();
+        for i in 0..rot_dim / 2 {
+            out[i] = head[2 * i];
+            out[i + rot_dim / 2] = head[2 * i + 1];
+        }
+        out
+    };
+    let mut interleaved = Tensor::new(x.clone(), &vec![3, n_heads, head_dim]);
+    rope(&mut interleaved, &cos, &sin, RopeLayout::Interleaved);
+    let mut halves = Tensor::new(
+        x.chunks(head_dim).flat_map(to_halves).collect(),
+        &vec![3, n_heads, head_dim],
+    );
+    rope(&mut halves, &cos, &sin, RopeLayout::Halves);
+    let rotated = interleaved
+        .data()
+        .chunks(head_dim)
+        .flat_map(to_halves)
+        .collect::<Vec<_>>();
+    assert!(halves.close_to(&Tensor::new(rotated, &vec![3, n_heads, head_dim]), 1e-6));
+
+    // only the first rot_dim dimensions of each head move
+    for (head_in, head_out) in x.chunks(head_dim).zip(interleaved.data().chunks(head_dim)) {
+        assert_eq!(head_in[rot_dim..], head_out[rot_dim..]);
+        assert_ne!(head_in[..rot_dim], head_out[..rot_dim]);
+    }
+    // and the first pair of the first token turned by position 2
+    let (a, b) = (x[0], x[1]);
+    assert!((interleaved.data()[0] - (a * 2f32.cos() - b * 2f32.sin())).abs() < 1e-6);
+    assert!((interleaved.data()[1] - (b * 2f32.cos() + a * 2f32.sin())).abs() < 1e-6);
+}
+
+#[test]
+fn test_rope_layout_config() {
+    let config = |extra: &str| {
+        let json = format!(
+            r#"{{"bos_token_id": 1, "eos_token_id": 2, "hidden_size": 256, "intermediate_size": 512,
+                "max_position_embeddings": 2048, "num_attention_heads": 4, "num_hidden_layers": 1,
+                "num_key_value_heads": 4, "vocab_size": 100, "torch_dtype": "float32"{extra}}}"#
+        );
+        RotaryEmbedding::from_config(&serde_json::from_str(&json).unwrap()).unwrap()
+    };
+    let llama = config(r#", "model_type": "llama""#);
+    assert_eq!((llama.layout(), llama.dim()), (RopeLayout::Halves, 64));
+    let phi = config(r#", "model_type": "phi", "partial_rotary_factor": 0.5"#);
+    assert_eq!((phi.layout(), phi.dim()), (RopeLayout::Halves, 32));
+    // 64 * 0.4 = 25.6 dimensions round down to 24
+    let odd = config(r#", "model_type": "phi", "partial_rotary_factor": 0.4"#);
+    assert_eq!(odd.dim(), 24);
+    let neox = config(r#", "model_type": "gpt_neox", "rotary_pct": 0.25"#);
+    assert_eq!((neox.layout(), neox.dim()), (RopeLayout::Halves, 16));
+    let gptj = config(r#", "model_type": "gptj", "rotary_dim": 16"#);
+    assert_eq!((gptj.layout(), gptj.dim()), (RopeLayout::Interleaved, 16));
+}
@@ -0,0 +1,455 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::backend::Backend;
+use crate::kvcache::{BlockPool, CacheError, KVCache};
+use crate::model::Llama;
+use crate::operators as OP;
+use crate::tensor::{Float, Tensor};
+
+pub type RequestId = usize;
+
+// How the tokens of one request are picked, see OP::random_sample
+#[derive(Clone, Copy, Debug)]
+pub struct SamplingParams {
+    pub max_len: usize, // new tokens to generate at most
+    pub top_p: f32,
+    pub top_k: u32,
+    pub temperature: f32,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            max_len: 500,
+            top_p: 0.8,
+            top_k: 30,
+            temperature: 1.,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SchedulerConfig {
+    pub max_running: usize, // requests decoded together; the rest wait in the queue
+    pub max_batch_tokens: usize, // tokens in one forward step, decode tokens first
+    pub prefill_chunk: usize, // prompt tokens of one request in one forward step
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_running: 8,
+            max_batch_tokens: 256,
+            prefill_chunk: 64,
+        }
+    }
+}
+
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestState {
+    Waiting,
+    Prefilling,
+    Decoding,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FinishReason {
+    Eos,         // the model produced the end token
+    Length,      // max_len tokens were generated
+    ContextFull, // the cache reached the model's max_seq_len
+}
+
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub state: RequestState,
+    pub prompt_len: usize,
+    pub prefilled: usize, // prompt tokens already in the cache
+    pub generated: usize,
+    pub max_len: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Completion {
+    pub id: RequestId,
+    pub tokens: Vec<u32>, // generated tokens, without bos and eos, as `Llama::generate` returns them
+    pub reason: FinishReason,
+}
+
+struct Request {
+    id: RequestId,
+    prompt: Vec<u32>,
+    params: SamplingParams,
+    cache: Option<KVCache<f32>>, // allocated on admission
+    prefilled: usize,
+    next: Option<u32>, // the last sampled token, fed in the next step
+    blocks: usize,     // pool blocks set aside for it
+    tokens: Vec<u32>,
+}
+
+#[allow(unused)]
+impl Request {
+    fn state(&self) -> RequestState {
+        match (&self.cache, self.next) {
+            (None, _) => RequestState::Waiting,
+            (Some(_), None) => RequestState::Prefilling,
+            (Some(_), Some(_)) => RequestState::Decoding,
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        Progress {
+            state: self.state(),
+            prompt_len: self.prompt.len(),
+            prefilled: self.prefilled,
+            generated: self.tokens.len(),
+            max_len: self.params.max_len,
+        }
+    }
+}
+
+// Continuous batching: every call to `step` admits waiting requests while there is room, runs one
+// forward pass over a decode token of every decoding request plus prompt chunks of the
+// prefilling ones, and retires the requests that finished. Each request has its own cache and
+// sampling parameters.
+#[allow(unused)]
+pub struct Scheduler<'a, T, B: Backend> {
+    model: &'a Llama<T, B>,
+    config: SchedulerConfig,
+    waiting: VecDeque<Request>,
+    running: Vec<Request>,
+    next_id: RequestId,
+    pool: Option<Arc<BlockPool<f32>>>,
+    reserved: usize, // pool blocks set aside for the running requests
+}
+
+#[allow(unused)]
+impl<'a, T: Float, B: Backend> Scheduler<'a, T, B> {
+    pub fn new(model: &'a Llama<T, B>, config: SchedulerConfig) -> Self {
+        assert!(config.max_running > 0 && config.prefill_chunk > 0);
+        assert!(
+            config.max_batch_tokens >= config.max_running,
+            "no room for a decode token of every request"
+        );
+        Self {
+            model,
+            config,
+            waiting: VecDeque::new(),
+            running: Vec::new(),
+            next_id: 0,
+            pool: None,
+            reserved: 0,
+        }
+    }
+
+    // Paged caches from `pool` instead of max_seq_len positions per request. A request is only
+    // admitted once the blocks for its prompt and max_len new tokens can be set aside, so the
+    // pool never runs dry mid-step unless something else takes blocks from it too.
+    pub fn with_block_pool(mut self, pool: Arc<BlockPool<f32>>) -> Self {
+        assert!(
+            self.is_idle(),
+            "the pool must be set before requests are submitted"
+        );
+        self.pool = Some(pool);
+        self
+    }
+
+    // queue a request; it is admitted by a later `step`
+    pub fn submit(&mut self, prompt: &[u32], params: SamplingParams) -> RequestId {
+        assert!(!prompt.is_empty(), "empty prompt");
+        assert!(
+            prompt.len() < self.model.max_seq_len(),
+            "prompt does not fit the context"
+        );
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiting.push_back(Request {
+            id,
+            prompt: prompt.to_vec(),
+            params,
+            cache: None,
+            prefilled: 0,
+            next: None,
+            blocks: 0,
+            tokens: Vec::new(),
+        });
+        id
+    }
+
+    // requests submitted but not admitted yet
+    pub fn queue_depth(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn num_running(&self) -> usize {
+        self.running.len()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.waiting.is_empty() && self.running.is_empty()
+    }
+
+    // None once the request has finished (its completion was returned by `step`) or if unknown
+    pub fn progress(&self, id: RequestId) -> Option<Progress> {
+        self.running
+            .iter()
+            .chain(&self.waiting)
+            .find(|r| r.id == id)
+            .map(Request::progress)
+    }
+
+    // One iteration: admit, run one forward pass, sample, retire. Returns the requests that
+    // finished. If the block pool runs short the step does nothing and can be retried once blocks
+    // are freed.
+    pub fn step(&mut self) -> Result<Vec<Completion>, CacheError> {
+        while self.running.len() < self.config.max_running {
+            let Some(request) = self.waiting.front() else {
+                break;
+            };
+            if let Some(pool) = &self.pool {
+                let limit =
+                    (request.prompt.len() + request.params.max_len).min(self.model.max_seq_len());
+                let blocks = pool.blocks_for(limit);
+                assert!(
+                    blocks <= pool.num_blocks(),
+                    "request {} can never fit the pool",
+                    request.id
+                );
+                if self.reserved + blocks > pool.num_blocks() {
+                    break;
+                }
+                self.reserved += blocks;
+                let mut request = self.waiting.pop_front().unwrap();
+                request.blocks = blocks;
+                request.cache = Some(self.model.new_paged_cache(pool));
+                self.running.push(request);
+            } else {
+                let mut request = self.waiting.pop_front().unwrap();
+                request.cache = Some(self.model.new_cache());
+                self.running.push(request);
+            }
+        }
+        if self.running.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // decode tokens first, then prompt chunks in admission order with what is left of the budget
+        let mut budget =
+            self.config.max_batch_tokens - self.running.iter().filter(|r| r.next.is_some()).count();
+        let mut batch = Vec::with_capacity(self.running.len()); // (index in running, input)
+        for (i, r) in self.running.iter().enumerate() {
+            if let Some(token) = r.next {
+                batch.push((i, vec![token]));
+            } else if budget > 0 {
+                let len = (r.prompt.len() - r.prefilled)
+                    .min(self.config.prefill_chunk)
+                    .min(budget);
+                budget -= len;
+                batch.push((i, r.prompt[r.prefilled..][..len].to_vec()));
+            }
+        }
+
+        let inputs = batch
+            .iter()
+            .map(|(_, t)| Tensor::new(t.clone(), &vec![t.len()]))
+            .collect::<Vec<_>>();
+        let mut in_batch = vec![false; self.running.len()];
+        batch.iter().for_each(|(i, _)| in_batch[*i] = true);
+        let mut caches = self
+            .running
+            .iter_mut()
+            .zip(in_batch)
+            .filter(|(_, b)| *b)
+            .map(|(r, _)| r.cache.as_mut().unwrap())
+            .collect::<Vec<_>>();
+        let logits = self
+            .model
+            .try_forward_batch(&inputs.iter().collect::<Vec<_>>(), &mut caches)?;
+
+        let mut finished = vec![None; self.running.len()];
+        for ((i, input), logits) in batch.iter().zip(&logits) {
+            let r = &mut self.running[*i];
+            if r.next.is_none() {
+                r.prefilled += input.len();
+                if r.prefilled < r.prompt.len() {
+                    continue; // the logits of a partial prompt are not a prediction
+                }
+            }
+            let p = r.params;
+            let token = OP::random_sample(logits, p.top_p, p.top_k, p.temperature);
+            r.next = Some(token);
+            if token == self.model.eos_token_id() {
+                finished[*i] = Some(FinishReason::Eos);
+                continue;
+            }
+            if token != self.model.bos_token_id() {
+                r.tokens.push(token);
+            }
+            // bos tokens are not returned but do take cache positions
+            let len = r.cache.as_ref().unwrap().len();
+            if r.tokens.len() >= p.max_len || len >= r.prompt.len() + p.max_len {
+                finished[*i] = Some(FinishReason::Length);
+            } else if len >= self.model.max_seq_len() {
+                finished[*i] = Some(FinishReason::ContextFull);
+            }
+        }
+
+        let mut completions = Vec::new();
+        let mut i = 0;
+        self.running.retain_mut(|r| {
+            let reason = finished[i];
+            i += 1;
+            match reason {
+                Some(reason) => {
+                    self.reserved -= r.blocks;
+                    let tokens = std::mem::take(&mut r.tokens);
+                    completions.push(Completion {
+                        id: r.id,
+                        tokens,
+                        reason,
+                    });
+                    false
+                }
+                None => true,
+            }
+        });
+        Ok(completions)
+    }
+
+    // step until every request submitted so far has finished; completions in finishing order
+    pub fn run(&mut self) -> Result<Vec<Completion>, CacheError> {
+        let mut completions = Vec::new();
+        while !self.is_idle() {
+            completions.extend(self.step()?);
+        }
+        Ok(completions)
+    }
+}
+
+#[test]
+fn test_scheduler_matches_generate() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(&model_dir);
+    let greedy = SamplingParams {
+        max_len: 12,
+        top_p: 1.,
+        top_k: 1,
+        temperature: 1.,
+    };
+
+    let prompts = [
+        vec![1, 100, 200, 300, 400, 500, 600],
+        vec![1, 7],
+        vec![1, 300, 301, 302, 303],
+    ];
+    // small chunks and budget, so prefill of one request overlaps decoding of the others
+    let config = SchedulerConfig {
+        max_running: 2,
+        max_batch_tokens: 4,
+        prefill_chunk: 3,
+    };
+    let mut scheduler = Scheduler::new(&model, config);
+    let first = scheduler.submit(&prompts[0], greedy);
+    let second = scheduler.submit(
+        &prompts[1],
+        SamplingParams {
+            max_len: 5,
+            ..greedy
+        },
+    );
+    assert_eq!(scheduler.queue_depth(), 2);
+
+    assert!(scheduler.step().unwrap().is_empty());
+    assert_eq!(scheduler.queue_depth(), 0);
+    assert_eq!(scheduler.num_running(), 2);
+    // the first prompt got a full chunk, the second what was left of the budget
+    let p = scheduler.progress(first).unwrap();
+    assert_eq!(
+        (p.state, p.prefilled, p.prompt_len),
+        (RequestState::Prefilling, 3, 7)
+    );
+    let p = scheduler.progress(second).unwrap();
+    assert_eq!(
+        (p.state, p.prefilled, p.generated),
+        (RequestState::Prefilling, 1, 0)
+    );
+
+    // a request submitted mid-run waits for a free slot
+    let third = scheduler.submit(&prompts[2], greedy);
+    scheduler.step().unwrap();
+    assert_eq!(scheduler.queue_depth(), 1);
+    assert_eq!(
+        scheduler.progress(third).unwrap().state,
+        RequestState::Waiting
+    );
+
+    let mut completions = scheduler.run().unwrap();
+    assert!(scheduler.is_idle() && scheduler.progress(first).is_none());
+    completions.sort_by_key(|c| c.id);
+    assert_eq!(
+        completions.iter().map(|c| c.id).collect::<Vec<_>>(),
+        [first, second, third]
+    );
+    for (c, (prompt, max_len)) in completions.iter().zip(prompts.iter().zip([12, 5, 12])) {
+        assert_eq!(c.tokens, model.generate(prompt, max_len, 1., 1, 1.));
+        assert_eq!(c.reason == FinishReason::Length, c.tokens.len() == max_len);
+    }
+}
+
+#[test]
+fn test_scheduler_block_pool() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(&model_dir);
+    let greedy = SamplingParams {
+        max_len: 10,
+        top_p: 1.,
+        top_k: 1,
+        temperature: 1.,
+    };
+
+    // room for two requests of up to 16 positions at a time
+    let pool = Arc::new(model.new_block_pool(8, 4));
+    let mut scheduler =
+        Scheduler::new(&model, SchedulerConfig::default()).with_block_pool(pool.clone());
+    let prompts = [
+        vec![1, 100, 200, 300, 400, 500],
+        vec![1, 7],
+        vec![1, 300, 301, 302],
+    ];
+    for prompt in &prompts {
+        scheduler.submit(prompt, greedy);
+    }
+    scheduler.step().unwrap();
+    assert_eq!((scheduler.num_running(), scheduler.queue_depth()), (2, 1));
+    // blocks are taken as the sequences grow, not when they are set aside
+    assert_eq!(pool.num_free(), 8 - 2 - 1);
+
+    // blocks taken by something else make a step fail without changing anything
+    let mut other = model.new_paged_cache(&pool);
+    other.increment(pool.num_free() * 4).unwrap();
+    let before = loop {
+        let before = scheduler.progress(0);
+        match scheduler.step() {
+            Ok(completions) => assert!(completions.is_empty()),
+            Err(e) => {
+                assert!(matches!(e, CacheError::PoolExhausted(_)));
+                break before;
+            }
+        }
+    };
+    assert_eq!(scheduler.progress(0), before);
+    drop(other);
+
+    let mut completions = scheduler.run().unwrap();
+    completions.sort_by_key(|c| c.id);
+    for (c, prompt) in completions.iter().zip(&prompts) {
+        assert_eq!(c.tokens, model.generate(prompt, 10, 1., 1, 1.));
+    }
+    assert_eq!(pool.num_free(), 8);
+}
@@ -1,46 +1,187 @@
-use std::{slice, sync::Arc, vec};
+use half::{bf16, f16};
+use std::{ops::Range, slice, sync::Arc, vec};
+
+// Element types weights can be stored in; compute always widens them to f32.
+pub trait Float: Copy + Clone + Default + Send + Sync + 'static {
+    fn to_f32(self) -> f32;
+    fn from_f32(x: f32) -> Self;
+    // the data itself when it is already f32, which lets kernels take their SIMD path
+    fn as_f32(_data: &[Self]) -> Option<&[f32]> {
+        None
+    }
+}
+
+impl Float for f32 {
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        self
+    }
+    #[inline(always)]
+    fn from_f32(x: f32) -> Self {
+        x
+    }
+    fn as_f32(data: &[Self]) -> Option<&[f32]> {
+        Some(data)
+    }
+}
+
+impl Float for f16 {
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+    #[inline(always)]
+    fn from_f32(x: f32) -> Self {
+        f16::from_f32(x)
+    }
+}
+
+impl Float for bf16 {
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        bf16::to_f32(self)
+    }
+    #[inline(always)]
+    fn from_f32(x: f32) -> Self {
+        bf16::from_f32(x)
+    }
+}
+
+// Memory owned by something else (e.g. a mapped model file), kept alive by `_owner`
+struct Borrowed<T> {
+    _owner: Arc<dyn AsRef<[u8]> + Send + Sync>,
+    ptr: *const T,
+    len: usize,
+}
+
+// The pointer is only ever read through and the owner is itself Send + Sync
+unsafe impl<T: Sync> Send for Borrowed<T> {}
+unsafe impl<T: Sync> Sync for Borrowed<T> {}
+
+impl<T> AsRef<[T]> for Borrowed<T> {
+    fn as_ref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
 #[derive(Clone)]
 pub struct Tensor<T> {
-    data: Arc<Box<[T]>>,
+    data: Arc<dyn AsRef<[T]> + Send + Sync>,
     shape: Vec<usize>,
+    strides: Vec<usize>, // elements to skip per step along each axis
     pub offset: usize,
     length: usize,
+    read_only: bool, // the buffer is borrowed, e.g. from a memory-mapped file, see `from_bytes`
 }
 
-impl<T: Copy + Clone + Default> Tensor<T> {
+#[allow(clippy::ptr_arg)]
+impl<T: Copy + Clone + Default + Send + Sync + 'static> Tensor<T> {
     pub fn new(data: Vec<T>, shape: &Vec<usize>) -> Self {
         let length = data.len();
         Tensor {
-            data: Arc::new(data.into_boxed_slice().try_into().unwrap()),
+            data: Arc::new(data.into_boxed_slice()),
             shape: shape.clone(),
+            strides: packed_strides(shape),
             offset: 0,
-            length: length,
+            length,
+            read_only: false,
         }
     }
 
+    // Zero-copy tensor over `bytes` of `owner`, or None if the range is misaligned for T.
+    // Safety: the bytes must hold valid little-endian values of T and must never be written to.
+    pub unsafe fn from_bytes(
+        owner: Arc<dyn AsRef<[u8]> + Send + Sync>,
+        bytes: Range<usize>,
+        shape: &Vec<usize>,
+    ) -> Option<Self> {
+        let length: usize = shape.iter().product();
+        assert_eq!(
+            bytes.len(),
+            length * size_of::<T>(),
+            "byte range does not match {shape:?}"
+        );
+        let ptr = (*owner).as_ref()[bytes].as_ptr();
+        if cfg!(target_endian = "big") || ptr.align_offset(align_of::<T>()) != 0 {
+            return None;
+        }
+        let data = Borrowed {
+            _owner: owner,
+            ptr: ptr as *const T,
+            len: length,
+        };
+        Some(Tensor {
+            data: Arc::new(data),
+            shape: shape.clone(),
+            strides: packed_strides(shape),
+            offset: 0,
+            length,
+            read_only: true,
+        })
+    }
+
     pub fn default(shape: &Vec<usize>) -> Self {
         let length = shape.iter().product();
         let data = vec![T::default(); length];
         Self::new(data, shape)
     }
 
+    // Packed data of a contiguous tensor; views must go through `contiguous()` first.
     pub fn data(&self) -> &[T] {
-        &self.data[self.offset..][..self.length]
+        assert!(
+            self.is_contiguous(),
+            "non-contiguous view, call contiguous() first"
+        );
+        &(*self.data).as_ref()[self.offset..][..self.length]
     }
 
+    // Panics on tensors borrowing a buffer, see `from_bytes`, whose memory may be read-only.
     pub unsafe fn data_mut(&mut self) -> &mut [T] {
-        let ptr = self.data.as_ptr().add(self.offset) as *mut T;
+        assert!(
+            !self.read_only,
+            "tensor borrows a read-only buffer, copy it first"
+        );
+        assert!(
+            self.is_contiguous(),
+            "non-contiguous view, call contiguous() first"
+        );
+        let ptr = (*self.data).as_ref().as_ptr().add(self.offset) as *mut T;
         slice::from_raw_parts_mut(ptr, self.length)
     }
 
+    // whether clones or views of this tensor share its buffer
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.data) > 1
+    }
+
+    // Underlying buffer starting at this view's offset, to be indexed with `strides()`.
+    pub fn strided_data(&self) -> &[T] {
+        &(*self.data).as_ref()[self.offset..][..self.span()]
+    }
+
     pub fn shape(&self) -> &Vec<usize> {
         &self.shape
     }
 
+    pub fn strides(&self) -> &Vec<usize> {
+        &self.strides
+    }
+
     pub fn size(&self) -> usize {
         self.length
     }
 
+    pub fn is_contiguous(&self) -> bool {
+        let mut expected = 1;
+        for (&dim, &stride) in self.shape.iter().zip(&self.strides).rev() {
+            if dim != 1 && stride != expected {
+                return false;
+            }
+            expected *= dim;
+        }
+        true
+    }
+
     // Reinterpret the tensor as a new shape while preserving total size.
     pub fn reshape(&mut self, new_shape: &Vec<usize>) -> &mut Self {
         let new_length: usize = new_shape.iter().product();
@@ -48,39 +189,115 @@ impl<T: Copy + Clone + Default> Tensor<T> {
             let old_shape = self.shape.clone();
             panic!("New shape {new_shape:?} does not match tensor of {old_shape:?}");
         }
+        assert!(self.is_contiguous(), "cannot reshape a non-contiguous view");
         self.shape = new_shape.clone();
+        self.strides = packed_strides(new_shape);
         self
     }
 
     pub fn slice(&self, start: usize, shape: &Vec<usize>) -> Self {
         let new_length: usize = shape.iter().product();
-        assert!(self.offset + start + new_length <= self.length);
+        assert!(self.is_contiguous(), "cannot slice a non-contiguous view");
+        assert!(start + new_length <= self.length);
         Tensor {
             data: self.data.clone(),
             shape: shape.clone(),
+            strides: packed_strides(shape),
             offset: self.offset + start,
             length: new_length,
+            read_only: self.read_only,
         }
     }
 
+    // View of `len` entries along `dim` starting at `start`, sharing the same buffer.
+    pub fn narrow(&self, dim: usize, start: usize, len: usize) -> Self {
+        assert!(start + len <= self.shape[dim], "narrow out of range");
+        let mut shape = self.shape.clone();
+        shape[dim] = len;
+        Tensor {
+            data: self.data.clone(),
+            length: shape.iter().product(),
+            shape,
+            strides: self.strides.clone(),
+            offset: self.offset + start * self.strides[dim],
+            read_only: self.read_only,
+        }
+    }
+
+    // View with axes reordered, `order[i]` is the old axis that becomes axis `i`.
+    pub fn permute(&self, order: &[usize]) -> Self {
+        let ndim = self.shape.len();
+        assert_eq!(order.len(), ndim, "permute needs one entry per axis");
+        let mut seen = vec![false; ndim];
+        for &axis in order {
+            assert!(axis < ndim && !seen[axis], "invalid permutation {order:?}");
+            seen[axis] = true;
+        }
+        Tensor {
+            data: self.data.clone(),
+            shape: order.iter().map(|&i| self.shape[i]).collect(),
+            strides: order.iter().map(|&i| self.strides[i]).collect(),
+            offset: self.offset,
+            length: self.length,
+            read_only: self.read_only,
+        }
+    }
+
+    pub fn transpose(&self, dim0: usize, dim1: usize) -> Self {
+        let mut order = (0..self.shape.len()).collect::<Vec<_>>();
+        order.swap(dim0, dim1);
+        self.permute(&order)
+    }
+
+    // (seq, n_heads * dqkv) -> (seq, dqkv) view of a single head
     pub fn select_head(&self, head_index: usize, n_heads: usize, dqkv: usize) -> Self {
-        let seq_len = self.shape[0];
-        let hidden_size = self.shape[1];
-        assert_eq!(hidden_size, n_heads * dqkv, "列数必须等于 n_heads * dqkv");
-
-        let mut new_tensor = Self::default(&vec![seq_len, dqkv]);
-        let origin_data = self.data();
-
-        let data = unsafe { new_tensor.data_mut() };
-        for i in 0..seq_len {
-            for offset in 0..dqkv {
-                let index = i * hidden_size + head_index * dqkv + offset;
-                data[i * dqkv + offset] = origin_data[index];
+        assert_eq!(self.shape.len(), 2);
+        assert_eq!(self.shape[1], n_heads * dqkv, "列数必须等于 n_heads * dqkv");
+        self.narrow(1, head_index * dqkv, dqkv)
+    }
+
+    // Packed copy of a view, or a cheap clone if the tensor is already contiguous.
+    pub fn contiguous(&self) -> Self {
+        if self.is_contiguous() {
+            return self.clone();
+        }
+        let src = self.strided_data();
+        let mut data = Vec::with_capacity(self.length);
+        let mut index = vec![0; self.shape.len()];
+        for _ in 0..self.length {
+            let pos: usize = index.iter().zip(&self.strides).map(|(i, s)| i * s).sum();
+            data.push(src[pos]);
+            for d in (0..index.len()).rev() {
+                index[d] += 1;
+                if index[d] < self.shape[d] {
+                    break;
+                }
+                index[d] = 0;
             }
         }
+        Tensor::new(data, &self.shape)
+    }
+
+    // number of buffer elements covered by this view
+    fn span(&self) -> usize {
+        if self.length == 0 {
+            return 0;
+        }
+        1 + self
+            .shape
+            .iter()
+            .zip(&self.strides)
+            .map(|(d, s)| (d - 1) * s)
+            .sum::<usize>()
+    }
+}
 
-        new_tensor
+fn packed_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (1..shape.len()).rev() {
+        strides[i - 1] = strides[i] * shape[i];
     }
+    strides
 }
 
 // Some helper functions for testing and debugging
@@ -90,19 +307,26 @@ impl Tensor<f32> {
         if self.shape() != other.shape() {
             return false;
         }
-        let a = self.data();
-        let b = other.data();
-        
-        return a.iter().zip(b).all(|(x, y)| float_eq(x, y, rel));
+        let a = self.contiguous();
+        let b = other.contiguous();
+
+        a.data()
+            .iter()
+            .zip(b.data())
+            .all(|(x, y)| float_eq(x, y, rel))
     }
     #[allow(unused)]
-    pub fn print(&self){
-        println!("shpae: {:?}, offset: {}, length: {}", self.shape, self.offset, self.length);
+    pub fn print(&self) {
+        println!(
+            "shape: {:?}, strides: {:?}, offset: {}, length: {}",
+            self.shape, self.strides, self.offset, self.length
+        );
+        let packed = self.contiguous();
         let dim = self.shape()[self.shape().len() - 1];
         let batch = self.length / dim;
         for i in 0..batch {
             let start = i * dim;
-            println!("{:?}", &self.data()[start..][..dim]);
+            println!("{:?}", &packed.data()[start..][..dim]);
         }
     }
 }
@@ -112,21 +336,53 @@ pub fn float_eq(x: &f32, y: &f32, rel: f32) -> bool {
     (x - y).abs() <= rel * (x.abs() + y.abs()) / 2.0
 }
 
-pub fn transpose<T: Copy + Clone + Default>(tensor: &Tensor<T>) -> Tensor<T> {
-    assert_eq!(tensor.shape().len(), 2, "只支持二维张量的转置");
+// largest |x - y| over two outputs
+#[cfg(test)]
+pub fn max_abs_diff(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).fold(0f32, |m, (x, y)| m.max((x - y).abs()))
+}
 
-    let rows = tensor.shape()[0];
-    let cols = tensor.shape()[1];
-    let mut result = Tensor::<T>::default(&vec![cols, rows]);
+#[test]
+fn test_transpose_view() {
+    let t = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let tt = t.transpose(0, 1);
+    assert_eq!(tt.shape(), &vec![3, 2]);
+    assert_eq!(tt.strides(), &vec![1, 3]);
+    assert!(!tt.is_contiguous());
+    assert_eq!(tt.contiguous().data(), &[1., 4., 2., 5., 3., 6.]);
+}
 
-    let origin_data = tensor.data();
-    let result_data = unsafe { result.data_mut() };
+#[test]
+fn test_select_head_view() {
+    // (seq = 2, n_heads = 3, dqkv = 2)
+    let t = Tensor::<f32>::new((0..12).map(|x| x as f32).collect(), &vec![2, 6]);
+    let head = t.select_head(1, 3, 2);
+    assert_eq!(head.shape(), &vec![2, 2]);
+    assert_eq!(head.contiguous().data(), &[2., 3., 8., 9.]);
+    let head_t = head.transpose(0, 1);
+    assert_eq!(head_t.contiguous().data(), &[2., 8., 3., 9.]);
+}
 
-    for i in 0..rows {
-        for j in 0..cols {
-            result_data[j * rows + i] = origin_data[i * cols + j].clone();
-        }
-    }
+#[test]
+fn test_permute_narrow() {
+    let t = Tensor::<f32>::new((0..24).map(|x| x as f32).collect(), &vec![2, 3, 4]);
+    let p = t.permute(&[1, 0, 2]).narrow(2, 1, 2);
+    assert_eq!(p.shape(), &vec![3, 2, 2]);
+    assert_eq!(
+        p.contiguous().data(),
+        &[1., 2., 13., 14., 5., 6., 17., 18., 9., 10., 21., 22.]
+    );
+}
 
-    result
-}
\ No newline at end of file
+#[test]
+fn test_borrowed_is_read_only() {
+    let owner: Arc<dyn AsRef<[u8]> + Send + Sync> = Arc::new(vec![0u8; 8]);
+    let t = unsafe { Tensor::<u8>::from_bytes(owner, 0..8, &vec![2, 4]) }.unwrap();
+    let mut row = t.slice(4, &vec![4]);
+    let write = std::panic::AssertUnwindSafe(move || unsafe { row.data_mut()[0] = 1 });
+    assert!(std::panic::catch_unwind(write).is_err());
+    // a copy can be written to
+    let mut copy = Tensor::new(t.data().to_vec(), t.shape());
+    unsafe { copy.data_mut()[0] = 1 };
+    assert_eq!(t.data()[0], 0);
+}